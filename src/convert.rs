@@ -0,0 +1,135 @@
+use std::convert::TryFrom;
+
+use crate::error::VMError;
+use crate::value::value::Val;
+
+// Conversions between Rust values and VM values, for passing arguments to
+// `VMState::call` and primitives and reading their results. Lists are
+// cons cells ending in the empty list, and `None` is nil.
+pub trait IntoVal {
+    fn into_val(self) -> Val;
+}
+
+pub trait FromVal: Sized {
+    fn from_val(v: &Val) -> Result<Self, VMError>;
+}
+
+fn mismatch(v: &Val, expected: &str) -> VMError {
+    VMError::runtime(format!("Expected {}, got {}", expected, v))
+}
+
+impl IntoVal for Val {
+    fn into_val(self) -> Val {
+        self
+    }
+}
+
+impl FromVal for Val {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        Ok(v.clone())
+    }
+}
+
+impl IntoVal for i32 {
+    fn into_val(self) -> Val {
+        Val::Num(self)
+    }
+}
+
+impl FromVal for i32 {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        v.as_num()
+    }
+}
+
+impl FromVal for i64 {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        Ok(v.as_num()?.into())
+    }
+}
+
+impl FromVal for usize {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        let n = v.as_num()?;
+        usize::try_from(n).map_err(|_| mismatch(v, "a non-negative number"))
+    }
+}
+
+impl IntoVal for bool {
+    fn into_val(self) -> Val {
+        Val::Bool(self)
+    }
+}
+
+impl FromVal for bool {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        match v {
+            Val::Bool(b) => Ok(*b),
+            _ => Err(mismatch(v, "a boolean")),
+        }
+    }
+}
+
+impl IntoVal for String {
+    fn into_val(self) -> Val {
+        Val::String(self)
+    }
+}
+
+impl IntoVal for &str {
+    fn into_val(self) -> Val {
+        Val::String(self.to_string())
+    }
+}
+
+impl FromVal for String {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        match v {
+            Val::String(s) => Ok(s.clone()),
+            _ => Err(mismatch(v, "a string")),
+        }
+    }
+}
+
+impl<T: IntoVal> IntoVal for Vec<T> {
+    fn into_val(self) -> Val {
+        self.into_iter().rev().fold(Val::EmptyList, |list, x| {
+            Val::Cons(Box::new(x.into_val()), Box::new(list))
+        })
+    }
+}
+
+impl<T: FromVal> FromVal for Vec<T> {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        let mut items = Vec::new();
+        let mut list = v;
+        loop {
+            match list {
+                Val::Cons(x, xs) => {
+                    items.push(T::from_val(x)?);
+                    list = xs;
+                }
+                Val::EmptyList => return Ok(items),
+                _ => return Err(mismatch(v, "a list")),
+            }
+        }
+    }
+}
+
+impl<T: IntoVal> IntoVal for Option<T> {
+    fn into_val(self) -> Val {
+        match self {
+            Some(x) => x.into_val(),
+            None => Val::Nil,
+        }
+    }
+}
+
+impl<T: FromVal> FromVal for Option<T> {
+    fn from_val(v: &Val) -> Result<Self, VMError> {
+        match v {
+            Val::Nil => Ok(None),
+            _ => T::from_val(v).map(Some),
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use crate::value::value::VMFunction;
+
+// Execution counts for every instruction of every function that has run,
+// and how many times each function was entered, keyed by function id.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    pub hits: HashMap<usize, Vec<u64>>,
+    pub entries: HashMap<usize, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    pub fn hit(&mut self, function: &VMFunction, pc: usize) {
+        let counts = self
+            .hits
+            .entry(function.id)
+            .or_insert_with(|| vec![0; function.instructions.len()]);
+        counts[pc] += 1;
+    }
+
+    // Counts a call of `function`, or the start of a program or coroutine
+    // running it. A loop back to its first instruction isn't one.
+    pub fn enter(&mut self, function: &VMFunction) {
+        *self.entries.entry(function.id).or_insert(0) += 1;
+    }
+
+    fn entries(&self, function: &VMFunction) -> u64 {
+        self.entries.get(&function.id).copied().unwrap_or(0)
+    }
+
+    fn counts(&self, function: &VMFunction) -> Vec<u64> {
+        match self.hits.get(&function.id) {
+            Some(counts) => counts.clone(),
+            None => vec![0; function.instructions.len()],
+        }
+    }
+
+    pub fn report(&self, functions: &[&VMFunction], out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<24} {:>10} {:>10} {:>8}",
+            "function", "executed", "total", "cover"
+        )?;
+        let (mut executed, mut total) = (0, 0);
+        for function in functions {
+            let counts = self.counts(function);
+            let covered = counts.iter().filter(|&&n| n > 0).count();
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>10} {:>7.1}%",
+                function.name,
+                covered,
+                counts.len(),
+                percent(covered, counts.len())
+            )?;
+            executed += covered;
+            total += counts.len();
+        }
+        writeln!(
+            out,
+            "{:<24} {:>10} {:>10} {:>7.1}%",
+            "TOTAL",
+            executed,
+            total,
+            percent(executed, total)
+        )
+    }
+
+    // Disassembles every function with the number of times each instruction
+    // ran, marking the ones that never did with #####.
+    pub fn write_listing(&self, functions: &[&VMFunction], out: &mut dyn Write) -> io::Result<()> {
+        for function in functions {
+            let counts = self.counts(function);
+            writeln!(out, "function {}", function.name)?;
+            for (pc, (instruction, count)) in function.instructions.iter().zip(counts).enumerate() {
+                let count = match count {
+                    0 => "#####".to_string(),
+                    n => n.to_string(),
+                };
+                writeln!(out, "{:>10} {:>5}: {}", count, pc, instruction)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Coverage {
+    // Line coverage in lcov's tracefile format, for the functions that have
+    // source positions. A line counts as executed as many times as its most
+    // executed instruction.
+    pub fn write_lcov(&self, functions: &[&VMFunction], out: &mut dyn Write) -> io::Result<()> {
+        let mut files: Vec<&str> = functions
+            .iter()
+            .filter_map(|f| f.source.as_deref())
+            .collect();
+        files.sort_unstable();
+        files.dedup();
+        for file in files {
+            writeln!(out, "SF:{}", file)?;
+            let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+            let in_file: Vec<&&VMFunction> = functions
+                .iter()
+                .filter(|f| f.source.as_deref() == Some(file))
+                .collect();
+            for function in in_file.iter() {
+                if let Some(line) = function.first_line() {
+                    writeln!(out, "FN:{},{}", line, function.name)?;
+                }
+            }
+            for function in in_file.iter() {
+                let counts = self.counts(function);
+                if function.first_line().is_some() {
+                    writeln!(out, "FNDA:{},{}", self.entries(function), function.name)?;
+                }
+                for (pc, count) in counts.into_iter().enumerate() {
+                    if let Some(position) = function.position(pc) {
+                        let hits = lines.entry(position.line).or_insert(0);
+                        *hits = (*hits).max(count);
+                    }
+                }
+            }
+            let named = in_file.iter().filter(|f| f.first_line().is_some());
+            writeln!(out, "FNF:{}", named.clone().count())?;
+            let called = named.filter(|f| self.entries(f) > 0);
+            writeln!(out, "FNH:{}", called.count())?;
+            for (line, hits) in lines.iter() {
+                writeln!(out, "DA:{},{}", line, hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|&&n| n > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        n as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        loader::load_str,
+        vmrun::{run, Status},
+        vmstate::init_vm_state,
+    };
+
+    #[test]
+    fn reports_and_marks_instructions_never_executed() {
+        // The goto jumps over the second loadliteral.
+        let mut vm = init_vm_state();
+        vm.coverage = Some(Coverage::new());
+        let module = load_str(
+            ".load module 5
+loadliteral 1 true
+if 1
+goto 2
+loadliteral 2 1
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module.clone()), Ok(Status::Halted));
+        let coverage = vm.coverage.unwrap();
+        let mut report = Vec::new();
+        coverage.report(&[&module], &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("\nmodule                            4          5    80.0%\n"));
+        let mut listing = Vec::new();
+        coverage.write_listing(&[&module], &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "function module");
+        assert!(
+            lines[3].starts_with("         1     2: goto"),
+            "{}",
+            lines[3]
+        );
+        assert!(
+            lines[4].starts_with("     #####     3: loadliteral"),
+            "{}",
+            lines[4]
+        );
+    }
+
+    // f counts its argument down to 0, looping back to its first
+    // instruction, and is called twice; g is never called.
+    const LOOPS: &str = ".load module 7
+.source string 8 112 114 111 103 46 115 99 109
+.line 1 1
+.load 0 function 1 5
+.name string 1 102
+.line 3 5
+loadliteral 2 1
+- 1 1 2
+if 1
+goto -3
+return 1
+.load 1 function 0 1
+.name string 1 103
+.line 9 5
+return 0
+.line 11 1
+loadliteral 1 2
+call 2 0 1
+loadliteral 1 2
+call 2 0 1
+halt
+";
+
+    // Runs `source` with coverage, returning it and every function run.
+    fn covered(source: &str) -> (Coverage, Vec<VMFunction>) {
+        let mut vm = init_vm_state();
+        vm.coverage = Some(Coverage::new());
+        let module = load_str(source, &mut vm).unwrap();
+        assert_eq!(run(&mut vm, module.clone()), Ok(Status::Halted));
+        let mut functions = vec![module];
+        functions.extend(vm.functions().cloned());
+        (vm.coverage.unwrap(), functions)
+    }
+
+    #[test]
+    fn writes_listings_with_counts() {
+        let (coverage, functions) = covered(LOOPS);
+        let functions: Vec<&VMFunction> = functions.iter().collect();
+        let mut listing = Vec::new();
+        coverage.write_listing(&functions, &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let f = listing.split("\n\n").nth(1).unwrap();
+        assert_eq!(
+            f,
+            "function f
+         4     0: loadliteral r2 lit0
+         4     1: - r1 r1 r2
+         4     2: if r1
+         2     3: goto -3
+         2     4: return r1"
+        );
+        assert!(listing.ends_with("function g\n     #####     0: return r0\n\n"));
+    }
+
+    #[test]
+    fn writes_lcov_with_function_entries() {
+        let (coverage, functions) = covered(LOOPS);
+        let functions: Vec<&VMFunction> = functions.iter().collect();
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&functions, &mut lcov).unwrap();
+        // f ran its first line four times but was entered only twice.
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "SF:prog.scm
+FN:1,module
+FN:3,f
+FN:9,g
+FNDA:1,module
+FNDA:2,f
+FNDA:0,g
+FNF:3
+FNH:2
+DA:1,1
+DA:3,4
+DA:9,0
+DA:11,1
+LF:4
+LH:3
+end_of_record
+"
+        );
+    }
+
+    #[test]
+    fn tells_apart_functions_with_the_same_name() {
+        // Defines f, calls it, then redefines it with a longer body.
+        let mut vm = init_vm_state();
+        vm.coverage = Some(Coverage::new());
+        let module = load_str(
+            ".load module 6
+.load 0 function 0 1
+return 0
+setglobal 0 string 1 102
+call 1 0 0
+.load 0 function 0 4
+loadliteral 1 1
+loadliteral 2 2
++ 1 1 2
+return 1
+setglobal 0 string 1 102
+call 1 0 0
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module.clone()), Ok(Status::Halted));
+        let mut functions = vec![&module];
+        functions.extend(vm.functions());
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["module", "f", "f@4"]);
+        let mut report = Vec::new();
+        vm.coverage
+            .as_ref()
+            .unwrap()
+            .report(&functions, &mut report)
+            .unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("\nf                                 1          1   100.0%\n"));
+        assert!(report.contains("\nf@4                               4          4   100.0%\n"));
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::{
+    debugger::{Debugger, Stop},
+    error::VMError,
+    input::Script,
+    loader,
+    output::Buffer,
+    value::value::VMFunction,
+    vmrun,
+    vmstate::{init_vm_state, VMState},
+};
+
+// Debug Adapter Protocol server. Functions with source positions are shown in
+// their source file. The others are presented to the editor as a source of
+// their own, one instruction per line, so breakpoints and stack frames are
+// expressed as (function, instruction index) like in `svm debug`.
+pub struct DapServer<R, W> {
+    input: R,
+    output: W,
+    seq: u64,
+    session: Option<Session>,
+}
+
+struct Session {
+    vm: VMState,
+    debugger: Debugger,
+    functions: Vec<VMFunction>,
+    breakpoints: HashMap<String, Vec<(String, usize)>>,
+    function_breakpoints: Vec<(String, usize)>,
+    stop_on_entry: bool,
+    // What the program prints, to be forwarded as output events; stdout
+    // itself carries the protocol.
+    printed: Buffer,
+    directory: PathBuf,
+}
+
+const GLOBALS_REFERENCE: u64 = 1;
+const THREAD_ID: u64 = 1;
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        DapServer {
+            input,
+            output,
+            seq: 0,
+            session: None,
+        }
+    }
+
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            let result = self.handle(&command, &request["arguments"]);
+            match result {
+                Ok(body) => self.respond(&request, true, body, None)?,
+                Err(message) => self.respond(&request, false, Value::Null, Some(message))?,
+            }
+            match command.as_str() {
+                "launch" if self.session.is_some() => self.send_event("initialized", json!({}))?,
+                "configurationDone" => self.start()?,
+                "continue" => self.resume(|s| s.debugger.resume(&mut s.vm, |_| false))?,
+                "next" => self.resume(|s| s.debugger.next(&mut s.vm))?,
+                "stepIn" => self.resume(|s| s.debugger.step(&mut s.vm, 1))?,
+                "stepOut" => self.resume(|s| s.debugger.step_out(&mut s.vm))?,
+                "disconnect" => return Ok(()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+            })),
+            "launch" => {
+                let program = args["program"].as_str().ok_or("launch needs a program")?;
+                let file = fs::File::open(program)
+                    .map_err(|e| format!("Failed to open {}: {}", program, e))?;
+                let directory = Path::new(program)
+                    .parent()
+                    .map(|p| p.to_path_buf())
+                    .unwrap_or_default();
+                let session = Session::new(
+                    file,
+                    directory,
+                    args["stopOnEntry"].as_bool().unwrap_or(false),
+                )
+                .map_err(|e| format!("Failed to load {}: {}", program, e))?;
+                self.session = Some(session);
+                Ok(Value::Null)
+            }
+            "disconnect" => Ok(Value::Null),
+            _ => {
+                let session = self.session.as_mut().ok_or("No program launched")?;
+                session.handle(command, args)
+            }
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        match &self.session {
+            Some(session) if session.stop_on_entry => self.stopped("entry", None),
+            Some(session) if vmrun::at_breakpoint(&session.vm) => self.stopped("breakpoint", None),
+            Some(_) => self.resume(|s| s.debugger.resume(&mut s.vm, |_| false)),
+            None => Ok(()),
+        }
+    }
+
+    fn resume(&mut self, action: impl FnOnce(&mut Session) -> Stop) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let stop = action(session);
+        let text = session.printed.take();
+        if !text.is_empty() {
+            self.send_event("output", json!({ "category": "stdout", "output": text }))?;
+        }
+        match stop {
+            Stop::Step => self.stopped("step", None),
+            Stop::Breakpoint => self.stopped("breakpoint", None),
+            Stop::Error(e) => self.stopped("exception", Some(e.to_string())),
+            Stop::Blocked => self.stopped("pause", Some("Waiting for input".to_string())),
+            Stop::Halted => {
+                let summary = self.session.as_ref().unwrap().vm.test_suite.summary();
+                self.send_event(
+                    "output",
+                    json!({ "category": "console", "output": summary + "\n" }),
+                )?;
+                self.send_event("exited", json!({ "exitCode": 0 }))?;
+                self.send_event("terminated", json!({}))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+            body["description"] = json!(text);
+        }
+        self.send_event("stopped", body)
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(n) = header.strip_prefix("Content-Length:") {
+                length = n.trim().parse().ok();
+            }
+        }
+        let length = length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+        })?;
+        let mut body = vec![0; length];
+        self.input.read_exact(&mut body)?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        success: bool,
+        body: Value,
+        message: Option<String>,
+    ) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+        });
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+impl Session {
+    fn new(file: fs::File, directory: PathBuf, stop_on_entry: bool) -> Result<Self, VMError> {
+        let mut vm = init_vm_state();
+        let printed = Buffer::new();
+        vm.output = Box::new(printed.clone());
+        // stdin carries the protocol, so the program gets no input.
+        vm.input = Box::new(Script::new(""));
+        let module = loader::load_reader(file, &mut vm)?;
+        let mut functions = vec![module.clone()];
+        functions.extend(vm.functions().cloned());
+        vmrun::start(&mut vm, module);
+        Ok(Session {
+            vm,
+            debugger: Debugger::new(),
+            functions,
+            breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            stop_on_entry,
+            printed,
+            directory,
+        })
+    }
+
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "setBreakpoints" => {
+                let lines: Vec<u64> = args["breakpoints"]
+                    .as_array()
+                    .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect())
+                    .unwrap_or_default();
+                let source = &args["source"];
+                let (key, resolved) = match source["path"].as_str() {
+                    Some(path) if self.functions.iter().any(|f| same_file(f, path)) => {
+                        (path.to_string(), self.source_breakpoints(path, &lines))
+                    }
+                    _ => {
+                        let function = self.source_function(source)?;
+                        let size = function.instructions.len();
+                        let resolved = lines
+                            .iter()
+                            .map(|&line| match line as usize {
+                                l if l >= 1 && l <= size => vec![(function.name.clone(), l - 1)],
+                                _ => vec![],
+                            })
+                            .collect();
+                        (function.name.clone(), resolved)
+                    }
+                };
+                let verified: Vec<Value> = lines
+                    .iter()
+                    .zip(resolved.iter())
+                    .map(|(line, at)| json!({ "verified": !at.is_empty(), "line": line }))
+                    .collect();
+                self.breakpoints.insert(key, resolved.concat());
+                self.update_breakpoints();
+                Ok(json!({ "breakpoints": verified }))
+            }
+            "setFunctionBreakpoints" => {
+                let names: Vec<&str> = args["breakpoints"]
+                    .as_array()
+                    .map(|bps| bps.iter().filter_map(|bp| bp["name"].as_str()).collect())
+                    .unwrap_or_default();
+                self.function_breakpoints.clear();
+                let mut verified = Vec::new();
+                for name in names {
+                    // `fn@4` breaks on entry, `fn@4:5` on instruction 5.
+                    let (function, index) = match name.rsplit_once(':') {
+                        Some((function, index)) => (function, index.parse().unwrap_or(0)),
+                        None => (name, 0),
+                    };
+                    verified.push(json!({ "verified": self.function(function).is_some() }));
+                    self.function_breakpoints
+                        .push((function.to_string(), index));
+                }
+                self.update_breakpoints();
+                Ok(json!({ "breakpoints": verified }))
+            }
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" => {
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => {
+                let frames: Vec<Value> = self
+                    .frames()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, (function, pc, _))| {
+                        match (&function.source, function.position(pc)) {
+                            (Some(path), Some(position)) => json!({
+                                "id": id,
+                                "name": function.name,
+                                "source": {
+                                    "name": file_name(path),
+                                    "path": self.directory.join(path),
+                                },
+                                "line": position.line,
+                                "column": position.column,
+                            }),
+                            _ => json!({
+                                "id": id,
+                                "name": function.name,
+                                "source": self.source(&function.name),
+                                "line": pc + 1,
+                                "column": 1,
+                            }),
+                        }
+                    })
+                    .collect();
+                Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+            }
+            "scopes" => {
+                let frame = args["frameId"].as_u64().ok_or("scopes needs a frameId")?;
+                Ok(json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": frame + 2, "expensive": false },
+                    { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+                ]}))
+            }
+            "variables" => {
+                let reference = args["variablesReference"]
+                    .as_u64()
+                    .ok_or("variables needs a variablesReference")?;
+                Ok(json!({ "variables": self.variables(reference)? }))
+            }
+            "source" => {
+                let reference = args["sourceReference"]
+                    .as_u64()
+                    .ok_or("source needs a sourceReference")?;
+                let function = (reference as usize)
+                    .checked_sub(1)
+                    .and_then(|n| self.functions.get(n))
+                    .ok_or_else(|| format!("Unknown source reference {}", reference))?;
+                let listing: Vec<String> = function
+                    .instructions
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect();
+                Ok(json!({ "content": listing.join("\n"), "mimeType": "text/plain" }))
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    fn function(&self, name: &str) -> Option<&VMFunction> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    fn source(&self, name: &str) -> Value {
+        let reference = self.functions.iter().position(|f| f.name == name).unwrap() + 1;
+        json!({ "name": name, "sourceReference": reference })
+    }
+
+    fn source_function(&self, source: &Value) -> Result<&VMFunction, String> {
+        match source["sourceReference"].as_u64() {
+            Some(reference) if reference > 0 => self.functions.get(reference as usize - 1),
+            _ => source["name"].as_str().and_then(|name| self.function(name)),
+        }
+        .ok_or_else(|| format!("Unknown source {}", source))
+    }
+
+    // For each line of a source file, the first instruction of every
+    // function that has code on it.
+    fn source_breakpoints(&self, path: &str, lines: &[u64]) -> Vec<Vec<(String, usize)>> {
+        lines
+            .iter()
+            .map(|&line| {
+                self.functions
+                    .iter()
+                    .filter(|f| same_file(f, path))
+                    .filter_map(|f| {
+                        (0..f.instructions.len())
+                            .find(|&pc| f.position(pc).is_some_and(|p| p.line as u64 == line))
+                            .map(|pc| (f.name.clone(), pc))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn update_breakpoints(&mut self) {
+        let mut all = self.function_breakpoints.clone();
+        for breakpoints in self.breakpoints.values() {
+            all.extend(breakpoints.iter().cloned());
+        }
+        self.vm.breakpoints = all;
+    }
+
+    // The current frame followed by its callers, as (function, pc, window).
+    fn frames(&self) -> Vec<(&VMFunction, usize, usize)> {
+        let mut frames = vec![(&self.vm.func, self.vm.pc, self.vm.reg_window)];
+        for act in self.vm.stack.iter().rev() {
+            frames.push((&act.fun, act.program_counter - 1, act.register_window));
+        }
+        frames
+    }
+
+    fn variables(&self, reference: u64) -> Result<Vec<Value>, String> {
+        if reference == GLOBALS_REFERENCE {
+            let mut globals: Vec<(String, String)> = self
+                .vm
+                .globals
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            globals.sort();
+            return Ok(globals
+                .into_iter()
+                .map(|(name, value)| variable(name, value))
+                .collect());
+        }
+        let frames = self.frames();
+        let (function, _, window) = (reference as usize)
+            .checked_sub(2)
+            .and_then(|n| frames.get(n))
+            .ok_or_else(|| format!("Unknown variables reference {}", reference))?;
+        Ok((0..function.registers_used())
+            .filter_map(|r| self.vm.registers.get(window + r).map(|v| (r, v)))
+            .map(|(r, v)| variable(format!("r{}", r), v.to_string()))
+            .collect())
+    }
+}
+
+// Whether `path`, as the editor names it, is the source file of `function`,
+// which may have been given relative to where the compiler ran.
+fn same_file(function: &VMFunction, path: &str) -> bool {
+    match &function.source {
+        Some(source) => source == path || Path::new(path).ends_with(source),
+        None => false,
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+fn variable(name: String, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+pub fn serve_stdio() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    DapServer::new(stdin.lock(), stdout.lock()).serve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: u64, command: &str, arguments: Value) -> String {
+        let body = json!({
+            "seq": seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    // Splits what the server wrote back into messages.
+    fn messages(output: &[u8]) -> Vec<Value> {
+        let mut output = std::str::from_utf8(output).unwrap();
+        let mut messages = Vec::new();
+        while let Some(rest) = output.strip_prefix("Content-Length: ") {
+            let (length, rest) = rest.split_once("\r\n\r\n").unwrap();
+            let length: usize = length.parse().unwrap();
+            messages.push(serde_json::from_str(&rest[..length]).unwrap());
+            output = &rest[length..];
+        }
+        assert!(output.is_empty());
+        messages
+    }
+
+    fn response(messages: &[Value], seq: u64) -> &Value {
+        messages
+            .iter()
+            .find(|m| m["type"] == "response" && m["request_seq"] == seq)
+            .unwrap()
+    }
+
+    #[test]
+    fn debugs_a_program_over_the_protocol() {
+        let path = std::env::temp_dir().join(format!("svm-dap-{}.vo", std::process::id()));
+        fs::write(
+            &path,
+            ".load module 5
+.load 0 function 1 2
++ 2 1 1
+return 2
+loadliteral 1 3
+call 2 0 1
+print 2
+halt
+",
+        )
+        .unwrap();
+        let requests = [
+            message(1, "initialize", json!({})),
+            message(2, "launch", json!({ "program": path })),
+            message(
+                3,
+                "setFunctionBreakpoints",
+                json!({ "breakpoints": [{ "name": "fn@0" }] }),
+            ),
+            message(4, "configurationDone", json!({})),
+            message(5, "stackTrace", json!({ "threadId": THREAD_ID })),
+            message(6, "variables", json!({ "variablesReference": 2 })),
+            message(7, "variables", json!({ "variablesReference": 0 })),
+            message(8, "source", json!({ "sourceReference": 0 })),
+            message(9, "pause", json!({ "threadId": THREAD_ID })),
+            message(10, "continue", json!({ "threadId": THREAD_ID })),
+            message(11, "disconnect", json!({})),
+        ]
+        .concat();
+        let mut output = Vec::new();
+        DapServer::new(requests.as_bytes(), &mut output)
+            .serve()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        let messages = messages(&output);
+
+        assert_eq!(response(&messages, 1)["body"].get("supportsPause"), None);
+        assert_eq!(
+            response(&messages, 3)["body"]["breakpoints"][0]["verified"],
+            true
+        );
+        let stopped: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .collect();
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        let frames = &response(&messages, 5)["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "fn@0");
+        assert_eq!(frames[1]["name"], "module");
+        let registers = &response(&messages, 6)["body"]["variables"];
+        assert_eq!(
+            registers[1],
+            json!({ "name": "r1", "value": "3", "variablesReference": 0 })
+        );
+        for seq in 7..=9 {
+            assert_eq!(response(&messages, seq)["success"], false);
+        }
+        let printed: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "output" && m["body"]["category"] == "stdout")
+            .collect();
+        assert_eq!(printed[0]["body"]["output"], "6\n");
+        assert!(messages.iter().any(|m| m["event"] == "terminated"));
+    }
+}
@@ -0,0 +1,435 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::{
+    error::VMError,
+    opcodes::Opcodes,
+    output::Output,
+    value::value::VMFunction,
+    vmrun::{self, Status},
+    vmstate::VMState,
+};
+
+// Commands for stepping through a program. Breakpoints are kept in
+// `vm.breakpoints`, where `vmrun::run_until` stops at them.
+pub struct Debugger {
+    halted: bool,
+}
+
+const HELP: &str = "\
+break FUNCTION [INDEX]  set a breakpoint (b)
+delete [N]              delete breakpoint N, or all breakpoints (d)
+breakpoints             list breakpoints
+step [N]                execute N instructions (s)
+next                    step over a call (n)
+continue                run until a breakpoint or the end of the program (c)
+registers [N]           print the first N registers of the current window (r)
+globals                 print global variables (g)
+backtrace               print the call stack (bt)
+list                    disassemble the current function (l)
+quit                    stop debugging (q)";
+
+pub enum Stop {
+    Step,
+    Breakpoint,
+    // The program is waiting for input; carrying on tries again.
+    Blocked,
+    Halted,
+    Error(VMError),
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger { halted: false }
+    }
+
+    // Reads commands from `input` until it ends or says to quit. What the
+    // debugger prints goes to `vm.output`, along with what the program
+    // prints.
+    pub fn run(
+        &mut self,
+        vm: &mut VMState,
+        function: VMFunction,
+        input: impl BufRead,
+    ) -> io::Result<()> {
+        vmrun::start(vm, function);
+        show_location(vm)?;
+        prompt(vm)?;
+        for line in input.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let stop = match words.as_slice() {
+                [] => None,
+                ["quit"] | ["q"] => return Ok(()),
+                ["help"] | ["h"] => {
+                    writeln!(vm.output, "{}", HELP)?;
+                    None
+                }
+                ["break", rest @ ..] | ["b", rest @ ..] => {
+                    add_breakpoint(vm, rest)?;
+                    None
+                }
+                ["delete", rest @ ..] | ["d", rest @ ..] => {
+                    delete_breakpoint(vm, rest)?;
+                    None
+                }
+                ["breakpoints"] => {
+                    list_breakpoints(vm)?;
+                    None
+                }
+                ["step"] | ["s"] => Some(self.step(vm, 1)),
+                ["step", n] | ["s", n] => match n.parse() {
+                    Ok(n) => Some(self.step(vm, n)),
+                    Err(_) => {
+                        writeln!(vm.output, "Expected a number of instructions, got {}", n)?;
+                        None
+                    }
+                },
+                ["next"] | ["n"] => Some(self.next(vm)),
+                ["continue"] | ["c"] => Some(self.resume(vm, |_| false)),
+                ["registers"] | ["r"] => {
+                    print_registers(vm, vm.func.registers_used())?;
+                    None
+                }
+                ["registers", n] | ["r", n] => {
+                    match n.parse() {
+                        Ok(n) => print_registers(vm, n)?,
+                        Err(_) => writeln!(vm.output, "Expected a number of registers, got {}", n)?,
+                    }
+                    None
+                }
+                ["globals"] | ["g"] => {
+                    print_globals(vm)?;
+                    None
+                }
+                ["backtrace"] | ["bt"] => {
+                    print_backtrace(vm)?;
+                    None
+                }
+                ["list"] | ["l"] => {
+                    print_listing(vm)?;
+                    None
+                }
+                _ => {
+                    writeln!(vm.output, "Unknown command: {} (try help)", line.trim())?;
+                    None
+                }
+            };
+            match stop {
+                None => {}
+                Some(Stop::Halted) => writeln!(vm.output, "Program halted")?,
+                Some(Stop::Blocked) => {
+                    write!(vm.output, "Waiting for input: ")?;
+                    show_location(vm)?;
+                }
+                Some(Stop::Step) => show_location(vm)?,
+                Some(Stop::Breakpoint) => {
+                    write!(vm.output, "Breakpoint: ")?;
+                    show_location(vm)?;
+                }
+                Some(Stop::Error(e)) => {
+                    write!(vm.output, "Error: {}: ", e)?;
+                    show_location(vm)?;
+                }
+            }
+            prompt(vm)?;
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self, vm: &mut VMState, n: usize) -> Stop {
+        self.run_until(vm, Some(n as u64), |_| false)
+    }
+
+    // Steps over a call, stopping once it has returned.
+    pub fn next(&mut self, vm: &mut VMState) -> Stop {
+        let depth = vm.stack.len();
+        let calling = match vm.func.instructions.get(vm.pc) {
+            Some(instruction) => matches!(
+                instruction.opcode,
+                Opcodes::Call | Opcodes::Apply | Opcodes::CallCC
+            ),
+            None => false,
+        };
+        self.resume(vm, |vm| !calling || vm.stack.len() <= depth)
+    }
+
+    // Runs until the current function returns to its caller.
+    pub fn step_out(&mut self, vm: &mut VMState) -> Stop {
+        let depth = vm.stack.len();
+        self.resume(vm, |vm| vm.stack.len() < depth)
+    }
+
+    // Executes instructions until `done` says to stop, a breakpoint is
+    // reached or the program halts. After an error the failed instruction
+    // stays current so that it and the registers it read can be inspected.
+    pub fn resume(&mut self, vm: &mut VMState, done: impl FnMut(&VMState) -> bool) -> Stop {
+        self.run_until(vm, None, done)
+    }
+
+    fn run_until(
+        &mut self,
+        vm: &mut VMState,
+        limit: Option<u64>,
+        done: impl FnMut(&VMState) -> bool,
+    ) -> Stop {
+        if self.halted {
+            return Stop::Halted;
+        }
+        match vmrun::run_until(vm, limit, done) {
+            Ok(Status::Breakpoint) if vmrun::at_breakpoint(vm) => Stop::Breakpoint,
+            Ok(Status::Breakpoint) | Ok(Status::Yielded) | Ok(Status::Running) => Stop::Step,
+            Ok(Status::BlockedOnInput) => Stop::Blocked,
+            Ok(Status::Halted) => {
+                self.halted = true;
+                Stop::Halted
+            }
+            Ok(Status::OutOfFuel) => {
+                self.halted = true;
+                Stop::Error(VMError::runtime("out of fuel"))
+            }
+            Ok(Status::TimedOut) => {
+                self.halted = true;
+                Stop::Error(VMError::runtime("timed out"))
+            }
+            Err(e) => {
+                self.halted = true;
+                Stop::Error(e)
+            }
+        }
+    }
+}
+
+// Standard input read a line at a time, for debugger commands. It doesn't
+// read ahead or keep stdin locked between lines, so the program being
+// debugged can read from stdin too, getting the lines typed while it runs.
+#[derive(Default)]
+pub struct StdinLines {
+    line: String,
+    at: usize,
+}
+
+impl Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.at == self.line.len() {
+            self.line.clear();
+            self.at = 0;
+            io::stdin().read_line(&mut self.line)?;
+        }
+        Ok(&self.line.as_bytes()[self.at..])
+    }
+    fn consume(&mut self, n: usize) {
+        self.at += n;
+    }
+}
+
+fn add_breakpoint(vm: &mut VMState, args: &[&str]) -> io::Result<()> {
+    let (function, index) = match args {
+        [function] => (function, Ok(0)),
+        [function, index] => (function, index.parse()),
+        _ => return writeln!(vm.output, "usage: break FUNCTION [INDEX]"),
+    };
+    match index {
+        Ok(index) => {
+            writeln!(
+                vm.output,
+                "Breakpoint {} at {} {}",
+                vm.breakpoints.len(),
+                function,
+                index
+            )?;
+            vm.breakpoints.push((function.to_string(), index));
+            Ok(())
+        }
+        Err(_) => writeln!(vm.output, "Expected an instruction index"),
+    }
+}
+
+fn delete_breakpoint(vm: &mut VMState, args: &[&str]) -> io::Result<()> {
+    match args {
+        [] => vm.breakpoints.clear(),
+        [n] => match n.parse::<usize>() {
+            Ok(n) if n < vm.breakpoints.len() => {
+                vm.breakpoints.remove(n);
+            }
+            _ => writeln!(vm.output, "No breakpoint {}", n)?,
+        },
+        _ => writeln!(vm.output, "usage: delete [N]")?,
+    }
+    Ok(())
+}
+
+fn list_breakpoints(vm: &mut VMState) -> io::Result<()> {
+    for (n, (function, index)) in vm.breakpoints.iter().enumerate() {
+        writeln!(vm.output, "{}: {} {}", n, function, index)?;
+    }
+    Ok(())
+}
+
+fn show_location(vm: &mut VMState) -> io::Result<()> {
+    let source = match vm.func.location(vm.pc) {
+        Some(location) => format!(" ({})", location),
+        None => String::new(),
+    };
+    match vm.func.instructions.get(vm.pc) {
+        Some(instruction) => writeln!(
+            vm.output,
+            "{} {}: {}{}",
+            vm.func.name, vm.pc, instruction, source
+        ),
+        None => writeln!(vm.output, "{} {}: <end>", vm.func.name, vm.pc),
+    }
+}
+
+fn prompt(vm: &mut VMState) -> io::Result<()> {
+    write!(vm.output, "(svm) ")?;
+    vm.output.flush()
+}
+
+// Prints the first `n` registers of the current window, or as many of them
+// as there are.
+fn print_registers(vm: &mut VMState, n: usize) -> io::Result<()> {
+    let window = &vm.registers[vm.reg_window..];
+    for (r, value) in window.iter().take(n).enumerate() {
+        writeln!(vm.output, "r{} = {}", r, value)?;
+    }
+    Ok(())
+}
+
+fn print_globals(vm: &mut VMState) -> io::Result<()> {
+    let mut globals: Vec<(String, String)> = vm
+        .globals
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    globals.sort();
+    for (name, value) in globals {
+        writeln!(vm.output, "{} = {}", name, value)?;
+    }
+    Ok(())
+}
+
+fn print_backtrace(vm: &mut VMState) -> io::Result<()> {
+    print_frame(&mut *vm.output, 0, &vm.func, vm.pc)?;
+    for (n, act) in vm.stack.iter().rev().enumerate() {
+        print_frame(&mut *vm.output, n + 1, &act.fun, act.program_counter - 1)?;
+    }
+    Ok(())
+}
+
+fn print_frame(out: &mut dyn Output, n: usize, function: &VMFunction, pc: usize) -> io::Result<()> {
+    match function.location(pc) {
+        Some(location) => writeln!(out, "#{} {} {} ({})", n, function.name, pc, location),
+        None => writeln!(out, "#{} {} {}", n, function.name, pc),
+    }
+}
+
+fn print_listing(vm: &mut VMState) -> io::Result<()> {
+    for (n, instruction) in vm.func.instructions.iter().enumerate() {
+        let marker = if n == vm.pc { "=>" } else { "  " };
+        writeln!(vm.output, "{} {:>4}: {}", marker, n, instruction)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::Script, loader::load_str, output::Buffer, value::value::Val, vmstate::init_vm_state,
+    };
+
+    const CALL: &str = ".load module 5
+.load 0 function 1 2 f
++ 2 1 1
+return 2
+loadliteral 1 3
+call 2 0 1
+print 2
+halt
+";
+
+    // Runs `CALL` under the debugger with `commands` and returns everything
+    // printed, by the debugger and the program.
+    fn debug(commands: &str) -> String {
+        let mut vm = init_vm_state();
+        let printed = Buffer::new();
+        vm.output = Box::new(printed.clone());
+        let module = load_str(CALL, &mut vm).unwrap();
+        Debugger::new()
+            .run(&mut vm, module, commands.as_bytes())
+            .unwrap();
+        printed.contents()
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let printed = debug("break f\ncontinue\nregisters 3\nbacktrace\ncontinue\n");
+        assert!(printed.contains("Breakpoint 0 at f 0"));
+        assert!(printed.contains("Breakpoint: f 0: "));
+        assert!(printed.contains("r1 = 3\n"));
+        assert!(printed.contains("#0 f 0\n#1 module 2\n"));
+        assert!(printed.contains("6\n"));
+        assert!(printed.ends_with("Program halted\n(svm) "));
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let printed = debug("step 2\nnext\nregisters 3\nquit\n");
+        assert!(printed.contains("module 3: "));
+        assert!(printed.contains("r2 = 6\n"));
+    }
+
+    #[test]
+    fn prints_no_more_registers_than_there_are() {
+        let printed = debug("registers 100000\n");
+        assert!(printed.contains("r49999 = nil\n"));
+        assert!(!printed.contains("r50000"));
+    }
+
+    #[test]
+    fn reports_waiting_for_input() {
+        let mut vm = init_vm_state();
+        let printed = Buffer::new();
+        vm.output = Box::new(printed.clone());
+        let input = Script::open();
+        vm.input = Box::new(input.clone());
+        let module = load_str(".load module 3\nread-line 1\nprint 1\nhalt\n", &mut vm).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.run(&mut vm, module, &b"continue\n"[..]).unwrap();
+        assert!(printed.take().contains("Waiting for input: module 0: "));
+        input.push("hello\n");
+        debugger.run_until(&mut vm, None, |_| false);
+        assert_eq!(vm.registers[1], Val::String("hello".to_string()));
+    }
+
+    #[test]
+    fn reports_running_out_of_fuel() {
+        let mut vm = init_vm_state();
+        let printed = Buffer::new();
+        vm.output = Box::new(printed.clone());
+        vm.fuel = Some(2);
+        let module = load_str(CALL, &mut vm).unwrap();
+        Debugger::new()
+            .run(&mut vm, module, &b"continue\ncontinue\n"[..])
+            .unwrap();
+        let printed = printed.contents();
+        assert!(printed.contains("Error: out of fuel: "));
+        assert!(printed.ends_with("Program halted\n(svm) "));
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt::{self, Display};
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VMError {
+    // A module that can't be loaded.
+    Load(String),
+    Runtime(String),
+    // A call from Rust used up `vm.fuel` or passed `vm.deadline`. Running
+    // the program stops with the matching status instead.
+    OutOfFuel,
+    TimedOut,
+}
+
+impl VMError {
+    pub fn runtime(message: impl Into<String>) -> Self {
+        VMError::Runtime(message.into())
+    }
+}
+
+impl From<io::Error> for VMError {
+    fn from(e: io::Error) -> Self {
+        VMError::Runtime(e.to_string())
+    }
+}
+
+impl Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMError::Load(message) => write!(f, "{}", message),
+            VMError::Runtime(message) => write!(f, "{}", message),
+            VMError::OutOfFuel => write!(f, "out of fuel"),
+            VMError::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
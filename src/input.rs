@@ -0,0 +1,329 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+
+use crate::error::VMError;
+use crate::value::value::Val;
+
+// Where a VM's input opcodes read from.
+pub trait Input {
+    // The next line, with its line terminator if it has one, or None at the
+    // end of the input. An error of kind `WouldBlock` means there is no more
+    // input yet, and the program waits for some.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+pub struct Stdin;
+
+impl Input for Stdin {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+}
+
+// Input supplied by whoever runs the VM. Clones share the same text, so one
+// can be given to the VM and another used to add to it while it runs.
+#[derive(Debug, Clone, Default)]
+pub struct Script(Rc<RefCell<ScriptText>>);
+
+#[derive(Debug, Default)]
+struct ScriptText {
+    text: String,
+    closed: bool,
+}
+
+impl Script {
+    // Input that consists of exactly `text`.
+    pub fn new(text: &str) -> Self {
+        let script = Script::open();
+        script.push(text);
+        script.close();
+        script
+    }
+    // Input that is added with `push` until `close`. Reading past what has
+    // been pushed so far blocks the program.
+    pub fn open() -> Self {
+        Script::default()
+    }
+    pub fn push(&self, text: &str) {
+        self.0.borrow_mut().text.push_str(text);
+    }
+    pub fn close(&self) {
+        self.0.borrow_mut().closed = true;
+    }
+}
+
+impl Input for Script {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut script = self.0.borrow_mut();
+        let end = match script.text.find('\n') {
+            Some(i) => i + 1,
+            None if !script.closed => return Err(io::ErrorKind::WouldBlock.into()),
+            None if script.text.is_empty() => return Ok(None),
+            None => script.text.len(),
+        };
+        Ok(Some(script.text.drain(..end).collect()))
+    }
+}
+
+enum Fill {
+    More,
+    End,
+    Blocked,
+}
+
+// Appends the next line of `input` to `unread`.
+fn fill(input: &mut dyn Input, unread: &mut String) -> Result<Fill, VMError> {
+    match input.read_line() {
+        Ok(Some(line)) => {
+            unread.push_str(&line);
+            Ok(Fill::More)
+        }
+        Ok(None) => Ok(Fill::End),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Fill::Blocked),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// What an input opcode reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reading {
+    // The rest of the current line as a string, without its terminator.
+    Line,
+    // The next whitespace-separated word, which has to be an integer.
+    Number,
+    // The next S-expression: a number, #t or #f, a string, a symbol, a
+    // quoted datum or a list of them, possibly dotted.
+    Datum,
+}
+
+// Reads from `input`, starting with what earlier reads left in `unread`, and
+// leaves there what this one doesn't use, so reading a number leaves the rest
+// of its line to the next `Reading::Line`. Returns None when the program has
+// to wait for more input.
+pub fn read(
+    input: &mut dyn Input,
+    unread: &mut String,
+    reading: Reading,
+) -> Result<Option<Val>, VMError> {
+    match reading {
+        Reading::Line => read_line(input, unread),
+        Reading::Number => read_with(input, unread, |parser| {
+            let word = parser.word()?;
+            word.parse()
+                .map(Val::Num)
+                .map_err(|_| Stop::Error(format!("read-number: {} isn't a number", word)))
+        }),
+        Reading::Datum => read_with(input, unread, |parser| parser.datum()),
+    }
+}
+
+fn read_line(input: &mut dyn Input, unread: &mut String) -> Result<Option<Val>, VMError> {
+    loop {
+        if let Some(i) = unread.find('\n') {
+            let line: String = unread.drain(..=i).collect();
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            return Ok(Some(Val::String(line.to_string())));
+        }
+        match fill(input, unread)? {
+            Fill::More => {}
+            Fill::Blocked => return Ok(None),
+            Fill::End if unread.is_empty() => return Ok(Some(Val::Eof)),
+            Fill::End => return Ok(Some(Val::String(std::mem::take(unread)))),
+        }
+    }
+}
+
+fn read_with(
+    input: &mut dyn Input,
+    unread: &mut String,
+    read: impl Fn(&mut Parser) -> Result<Val, Stop>,
+) -> Result<Option<Val>, VMError> {
+    let mut end = false;
+    loop {
+        let mut parser = Parser {
+            text: unread,
+            at: 0,
+            end,
+        };
+        let result = match parser.skip_space() {
+            Ok(()) if parser.at == parser.text.len() && end => Ok(Val::Eof),
+            Ok(()) => read(&mut parser),
+            Err(stop) => Err(stop),
+        };
+        let at = parser.at;
+        match result {
+            Ok(v) => {
+                unread.drain(..at);
+                return Ok(Some(v));
+            }
+            Err(Stop::Error(message)) => {
+                unread.drain(..at);
+                return Err(VMError::Runtime(message));
+            }
+            Err(Stop::Incomplete) if end => {
+                unread.clear();
+                return Err(VMError::runtime("read: the input ends inside a datum"));
+            }
+            Err(Stop::Incomplete) => match fill(input, unread)? {
+                Fill::More => {}
+                Fill::End => end = true,
+                Fill::Blocked => return Ok(None),
+            },
+        }
+    }
+}
+
+enum Stop {
+    // The text read so far is the start of something that continues on the
+    // next line.
+    Incomplete,
+    Error(String),
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    at: usize,
+    // Whether `text` is the rest of the input.
+    end: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.at..].chars().next()
+    }
+
+    fn next(&mut self) -> Result<char, Stop> {
+        let c = self.peek().ok_or(Stop::Incomplete)?;
+        self.at += c.len_utf8();
+        Ok(c)
+    }
+
+    // Skips whitespace and comments.
+    fn skip_space(&mut self) -> Result<(), Stop> {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                match self.text[self.at..].find('\n') {
+                    Some(i) => self.at += i,
+                    None if self.end => self.at = self.text.len(),
+                    None => return Err(Stop::Incomplete),
+                }
+            } else if c.is_whitespace() {
+                self.at += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Characters up to whitespace or a delimiter. A word at the end of the
+    // text may go on in the next line, unless the input ends there.
+    fn word(&mut self) -> Result<&'a str, Stop> {
+        let rest = &self.text[self.at..];
+        let len = match rest.find(|c: char| c.is_whitespace() || "()[]\";'".contains(c)) {
+            Some(len) => len,
+            None if self.end => rest.len(),
+            None => return Err(Stop::Incomplete),
+        };
+        self.at += len;
+        Ok(&rest[..len])
+    }
+
+    fn datum(&mut self) -> Result<Val, Stop> {
+        self.skip_space()?;
+        match self.peek() {
+            None => Err(Stop::Incomplete),
+            Some('(') | Some('[') => {
+                self.at += 1;
+                self.list()
+            }
+            Some(c @ ')') | Some(c @ ']') => {
+                self.at += 1;
+                Err(Stop::Error(format!("read: unexpected {}", c)))
+            }
+            Some('\'') => {
+                self.at += 1;
+                let quoted = self.datum()?;
+                Ok(cons(
+                    Val::String("quote".to_string()),
+                    cons(quoted, Val::EmptyList),
+                ))
+            }
+            Some('"') => {
+                self.at += 1;
+                self.string()
+            }
+            Some(_) => {
+                let word = self.word()?;
+                Ok(match word {
+                    "#t" => Val::Bool(true),
+                    "#f" => Val::Bool(false),
+                    _ => match word.parse() {
+                        Ok(n) => Val::Num(n),
+                        Err(_) => Val::String(word.to_string()),
+                    },
+                })
+            }
+        }
+    }
+
+    // The elements of a list after its opening bracket.
+    fn list(&mut self) -> Result<Val, Stop> {
+        let mut elements = Vec::new();
+        let mut tail = Val::EmptyList;
+        loop {
+            self.skip_space()?;
+            match self.peek() {
+                None => return Err(Stop::Incomplete),
+                Some(')') | Some(']') => {
+                    self.at += 1;
+                    break;
+                }
+                Some('.') if self.text[self.at + 1..].starts_with(char::is_whitespace) => {
+                    self.at += 1;
+                    if elements.is_empty() {
+                        return Err(Stop::Error("read: nothing before .".to_string()));
+                    }
+                    tail = self.datum()?;
+                    self.skip_space()?;
+                    match self.next()? {
+                        ')' | ']' => break,
+                        c => {
+                            return Err(Stop::Error(format!(
+                                "read: expected ) after . datum, got {}",
+                                c
+                            )))
+                        }
+                    }
+                }
+                Some(_) => elements.push(self.datum()?),
+            }
+        }
+        Ok(elements.into_iter().rev().fold(tail, |xs, x| cons(x, xs)))
+    }
+
+    // The rest of a string after its opening quote.
+    fn string(&mut self) -> Result<Val, Stop> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(Val::String(s)),
+                '\\' => match self.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+fn cons(x: Val, xs: Val) -> Val {
+    Val::Cons(Box::new(x), Box::new(xs))
+}
@@ -0,0 +1,34 @@
+// The svm virtual machine as a library. A typical embedding:
+//
+//     let mut vm = svm::VMState::new();
+//     let module = vm.load_str(source)?;
+//     vm.run(module)?;
+//     let answer = vm.global("answer");
+//     let passed = vm.test_suite.passed();
+pub mod convert;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod error;
+pub mod input;
+pub mod loader;
+pub mod memory;
+pub mod opcodes;
+pub mod output;
+pub mod ports;
+pub mod primitives;
+pub mod profile;
+pub mod repl;
+pub mod trace;
+pub mod value;
+pub mod vmrun;
+pub mod vmstack;
+pub mod vmstate;
+
+pub use convert::{FromVal, IntoVal};
+pub use error::VMError;
+pub use input::Input;
+pub use output::Output;
+pub use value::value::{VMFunction, Val};
+pub use vmrun::Status;
+pub use vmstate::{Tester, VMState};
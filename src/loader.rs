@@ -1,33 +1,58 @@
-use core::panic;
-use either::Either;
-use nom::{self, character::complete::digit1, IResult};
+use nom::{self, character::complete::digit1, error::ErrorKind, IResult};
+use std::io::Read;
+use std::str::FromStr;
 use std::{collections::HashMap, iter::FromIterator};
-use std::{fs::File, io::*};
-use value::value::VMFunction;
+use value::value::{Position, VMFunction};
 
 use crate::{
-    opcodes::{Instruction, InstructionParser, Opcodes},
+    error::VMError,
+    opcodes::{get_parsers, Instruction, InstructionParser, Opcodes},
     value::{self, value::Val},
     vmstate::VMState,
 };
 
-pub fn load_modules(
-    x: Either<Stdin, File>,
-    parser_map: &HashMap<String, (InstructionParser, Opcodes)>,
-    vm: &mut VMState,
-) -> VMFunction {
+// Loads a module in the .vo text format, adding its literals to `vm`, and
+// returns the module's top-level function.
+pub fn load_str(input: &str, vm: &mut VMState) -> Result<VMFunction, VMError> {
+    match parse_modules(input, &get_parsers(), vm) {
+        Ok((_, module)) => Ok(module),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+            let line = input[..input.len() - e.input.len()].matches('\n').count() + 1;
+            let near = e.input.split_whitespace().next().unwrap_or("end of input");
+            Err(VMError::Load(format!(
+                "line {}: can't parse {}",
+                line, near
+            )))
+        }
+        Err(nom::Err::Incomplete(_)) => Err(VMError::Load("incomplete module".to_string())),
+    }
+}
+
+pub fn load_reader(mut reader: impl Read, vm: &mut VMState) -> Result<VMFunction, VMError> {
     let mut buf = String::new();
-    match x {
-        Either::Left(mut stdin) => stdin
-            .read_to_string(&mut buf)
-            .expect("Failed to read from stdin"),
-        Either::Right(mut file) => file.read_to_string(&mut buf).expect("Failed to read file"),
-    };
+    reader
+        .read_to_string(&mut buf)
+        .map_err(|e| VMError::Load(e.to_string()))?;
+    load_str(&buf, vm)
+}
 
-    match parse_modules(&buf, parser_map, vm) {
-        Ok((_, foo)) => foo,
-        Err(e) => panic!("Wrong input {}", e),
-    }
+pub fn load_bytes(bytes: &[u8], vm: &mut VMState) -> Result<VMFunction, VMError> {
+    let input = std::str::from_utf8(bytes).map_err(|e| VMError::Load(e.to_string()))?;
+    load_str(input, vm)
+}
+
+// A parse error at `input` that stops the loader instead of letting it try
+// another alternative.
+fn failure(input: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Failure(nom::error::Error::new(input, ErrorKind::Verify))
+}
+
+// Digits read as a number of type `T`. One too big for `T` fails to load
+// rather than being cut short.
+fn number<T: FromStr>(input: &str) -> IResult<&str, T> {
+    let (rest, digits) = digit1(input)?;
+    let n = digits.parse().map_err(|_| failure(input))?;
+    Ok((rest, n))
 }
 
 fn parse_modules<'a>(
@@ -38,71 +63,170 @@ fn parse_modules<'a>(
     let r: IResult<&str, &str> = nom::bytes::complete::tag(".load module")(input);
     if let Ok((rest, _)) = r {
         let (rest, _) = nom::character::complete::multispace0(rest)?;
-        let (rest, str_size) = digit1(rest)?;
-        let size = str_size.parse().unwrap();
-        parse_module(0, size, rest, parser_map, vm)
+        let (rest, size) = number(rest)?;
+        parse_module("module", 0, size, None, rest, parser_map, vm)
     } else {
-        panic!("failed parsing")
+        Err(failure(input))
     }
 }
 
+// Besides its `count` instructions, a function may contain debug directives,
+// which are not counted:
+//   .source string N c1 ... cN   the source file of this and nested functions
+//   .line L C                    the line and column of the instructions after it
+//   .name string N c1 ... cN     the name of this function
+// A nested `.load R function A L` whose arity is written `A+` takes A
+// arguments followed by any number more, passed as a list in register A + 1.
 fn parse_module<'a>(
+    name: &str,
     arity: i32,
     count: i32,
+    source: Option<String>,
     rest: &'a str,
     parser_map: &HashMap<String, (InstructionParser, Opcodes)>,
     vm: &mut VMState,
 ) -> IResult<&'a str, VMFunction> {
+    vm.functions_loaded += 1;
     let mut vm_function = VMFunction {
+        name: name.to_string(),
+        id: vm.functions_loaded,
         size: count,
-        arity: arity,
+        arity,
+        variadic: false,
         nregs: 0,
         instructions: Vec::new(),
+        source,
+        positions: Vec::new(),
     };
+    let mut position = None;
+    let mut unnamed_functions = Vec::new();
     let mut stream = rest;
-    for _ in 0..count {
+    while vm_function.instructions.len() < count as usize {
         let (rest, _) = nom::character::complete::multispace0(stream)?;
         let (rest, name) = nom::bytes::complete::is_not(" \t\n")(rest)?;
-        if name == ".load" {
+        if name == ".source" {
             let (rest, _) = nom::character::complete::multispace0(rest)?;
-            let (rest, reg) = nom::character::complete::digit1(rest)?;
+            let (rest, source) = parse_string(rest)?;
+            vm_function.source = Some(source);
+            stream = rest;
+        } else if name == ".line" {
+            let (rest, _) = nom::character::complete::multispace0(rest)?;
+            let (rest, line) = number(rest)?;
+            let (rest, _) = nom::character::complete::multispace0(rest)?;
+            let (rest, column) = number(rest)?;
+            position = Some(Position { line, column });
+            stream = rest;
+        } else if name == ".name" {
+            let (rest, _) = nom::character::complete::multispace0(rest)?;
+            let (rest, name) = parse_string(rest)?;
+            vm_function.name = name;
+            stream = rest;
+        } else if name == ".load" {
+            let (rest, _) = nom::character::complete::multispace0(rest)?;
+            let (rest, reg) = number(rest)?;
             let (rest, _fun_name) = nom::bytes::complete::tag(" function ")(rest)?;
-            let (rest, fun_arity) = nom::character::complete::digit1(rest)?;
+            let (rest, fun_arity) = number(rest)?;
+            let (rest, variadic) = nom::combinator::opt(nom::bytes::complete::tag("+"))(rest)?;
             let (rest, _) = nom::character::complete::multispace0(rest)?;
-            let (rest, fun_length) = nom::character::complete::digit1(rest)?;
-            let (rest, func) = parse_module(
-                fun_arity.parse().unwrap(),
-                fun_length.parse().unwrap(),
+            let (rest, fun_length) = number(rest)?;
+            let (rest, _) = nom::character::complete::space0(rest)?;
+            let (rest, fun_name) =
+                nom::combinator::opt(nom::bytes::complete::is_not(" \t\r\n"))(rest)?;
+            let (rest, mut func) = parse_module(
+                fun_name.unwrap_or(""),
+                fun_arity,
+                fun_length,
+                vm_function.source.clone(),
                 rest,
                 parser_map,
                 vm,
             )?;
 
+            func.variadic = variadic.is_some();
+            let unnamed = func.name.is_empty();
+            if unnamed {
+                func.name = format!("fn@{}", vm.literals.len());
+            } else {
+                func.name = unique_name(&func.name, vm.literals.len(), vm);
+            }
             let slot = vm.literal_slot(Val::VMFunction(func));
-            let i = Instruction::eru16(Opcodes::LoadLiteral, slot, reg.parse().unwrap());
+            if unnamed {
+                unnamed_functions.push(slot);
+            }
+            let i = Instruction::eru16(Opcodes::LoadLiteral, slot, reg);
             vm_function.instructions.push(i);
+            vm_function.positions.push(position);
             stream = rest;
         } else {
             let (rest, instruction) = parse_instruction(stream, parser_map, vm)?;
             stream = rest;
             vm_function.instructions.push(instruction);
+            vm_function.positions.push(position);
         }
     }
+    name_functions(&vm_function, &unnamed_functions, vm);
     Ok((stream, vm_function))
 }
 
+// Names each function loaded without a name after the global it is first
+// stored in, following it through the registers of `parent` in program order.
+fn name_functions(parent: &VMFunction, unnamed: &[usize], vm: &mut VMState) {
+    let mut held: HashMap<usize, usize> = HashMap::new();
+    let mut unnamed = unnamed.to_vec();
+    for instruction in parent.instructions.iter() {
+        let (x, y) = (instruction.r_x, instruction.r_y);
+        match instruction.opcode {
+            Opcodes::LoadLiteral if unnamed.contains(&instruction.slot) => {
+                held.insert(x, instruction.slot);
+            }
+            Opcodes::Mov | Opcodes::MakeClosure => match held.get(&y).copied() {
+                Some(slot) => {
+                    held.insert(x, slot);
+                }
+                None => {
+                    held.remove(&x);
+                }
+            },
+            Opcodes::SetGlobal => {
+                if let (Some(&slot), Val::String(name)) =
+                    (held.get(&x), &vm.literals[instruction.slot])
+                {
+                    let name = unique_name(name, slot, vm);
+                    if let Val::VMFunction(f) = &mut vm.literals[slot] {
+                        f.name = name;
+                    }
+                    unnamed.retain(|&s| s != slot);
+                    held.retain(|_, s| *s != slot);
+                }
+            }
+            _ => {
+                held.remove(&x);
+            }
+        }
+    }
+}
+
+// `name`, or `name@slot` for the function in literal `slot` when another
+// function already has the name, as when a global is redefined. Profiles,
+// coverage reports and breakpoints then name one function each.
+fn unique_name(name: &str, slot: usize, vm: &VMState) -> String {
+    if vm.functions().any(|f| f.name == name) {
+        format!("{}@{}", name, slot)
+    } else {
+        name.to_string()
+    }
+}
+
 fn parse_instruction<'a>(
     s: &'a str,
     parser_map: &HashMap<String, (InstructionParser, Opcodes)>,
     vm: &mut VMState,
 ) -> IResult<&'a str, Instruction> {
-    let (rest, _) = nom::character::complete::multispace0(s)?;
+    let (start, _) = nom::character::complete::multispace0(s)?;
     let (rest, instruction_name) =
-        nom::multi::many1(nom::character::complete::none_of(" \t\n\r"))(rest)?;
+        nom::multi::many1(nom::character::complete::none_of(" \t\n\r"))(start)?;
     let ins = String::from_iter(instruction_name);
-    let (parser, opcode) = parser_map
-        .get(&ins)
-        .expect(&format!("Unknown instruction: {}", ins));
+    let (parser, opcode) = parser_map.get(&ins).ok_or_else(|| failure(start))?;
     let (rest, instruction) = match parser {
         InstructionParser::R3 => parse_r3(opcode, rest),
         InstructionParser::R2 => parse_r2(opcode, rest),
@@ -115,20 +239,16 @@ fn parse_instruction<'a>(
 }
 
 fn parse_r0i24<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction> {
-    let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, v) = nom::sequence::pair(
+    let (start, _) = nom::character::complete::multispace0(rest)?;
+    let (rest, offset) = nom::combinator::recognize(nom::sequence::pair(
         nom::combinator::opt(nom::bytes::complete::tag("-")),
-        nom::character::complete::digit1,
-    )(rest)?;
-    let num: i32 = if !v.0.is_none() {
-        v.1.parse::<i32>().unwrap() * -1
-    } else {
-        v.1.parse().unwrap()
-    };
+        digit1,
+    ))(start)?;
+    let num = offset.parse().map_err(|_| failure(start))?;
     Ok((
         rest,
         Instruction {
-            opcode: opcode.clone(),
+            opcode: *opcode,
             r_x: 0,
             r_y: 0,
             r_z: 0,
@@ -140,15 +260,15 @@ fn parse_r0i24<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruct
 
 fn parse_r2<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction> {
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_x) = nom::character::complete::digit1(rest)?;
+    let (rest, r_x) = number(rest)?;
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_y) = nom::character::complete::digit1(rest)?;
+    let (rest, r_y) = number(rest)?;
     Ok((
         rest,
         Instruction {
-            opcode: opcode.clone(),
-            r_x: r_x.parse().unwrap(),
-            r_y: r_y.parse().unwrap(),
+            opcode: *opcode,
+            r_x,
+            r_y,
             r_z: 0,
             slot: 0,
             goto: 0,
@@ -163,7 +283,7 @@ fn parse_r0<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction
             r_x: 0,
             r_y: 0,
             r_z: 0,
-            opcode: opcode.clone(),
+            opcode: *opcode,
             slot: 0,
             goto: 0,
         },
@@ -176,26 +296,26 @@ fn parse_r1lit<'a>(
     rest: &'a str,
 ) -> IResult<&'a str, Instruction> {
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, reg) = nom::character::complete::digit1(rest)?;
+    let (rest, reg) = number(rest)?;
     let (rest, _) = nom::character::complete::multispace0(rest)?;
     let (_, first_token) = nom::character::complete::anychar(rest)?;
     match first_token.is_ascii_digit() {
         true => {
-            let (rest, num) = nom::character::complete::digit1(rest)?;
-            let slot = vm.literal_slot(Val::to_num(num.parse().unwrap()));
+            let (rest, num) = number(rest)?;
+            let slot = vm.literal_slot(Val::to_num(num));
             Ok((
                 rest,
                 Instruction {
-                    r_x: reg.parse().unwrap(),
+                    r_x: reg,
                     r_y: 0,
                     r_z: 0,
-                    opcode: opcode.clone(),
+                    opcode: *opcode,
                     slot,
                     goto: 0,
                 },
             ))
         }
-        false => parse_complex_lit(vm, opcode, rest, reg.parse().unwrap()),
+        false => parse_complex_lit(vm, opcode, rest, reg),
     }
 }
 
@@ -207,17 +327,7 @@ fn parse_complex_lit<'a>(
 ) -> IResult<&'a str, Instruction> {
     let (rest, t) = nom::character::complete::alphanumeric1(rest)?;
     if t == "string" {
-        let (rest, _) = nom::character::complete::multispace0(rest)?;
-        let (rest, num) = nom::character::complete::digit1(rest)?;
-        let (rest, s) = nom::multi::count(
-            nom::sequence::preceded(
-                nom::character::complete::multispace0,
-                nom::character::complete::alphanumeric1,
-            ),
-            num.parse().unwrap(),
-        )(rest)?;
-        let u_s: Vec<u8> = s.into_iter().map(|x| x.parse::<u8>().unwrap()).collect();
-        let s = u_s.into_iter().map(|x| x as char).collect();
+        let (rest, s) = parse_string_body(rest)?;
         let v = Val::String(s);
         let slot = vm.literal_slot(v);
         Ok((
@@ -226,7 +336,7 @@ fn parse_complex_lit<'a>(
                 r_x: reg,
                 r_y: 0,
                 r_z: 0,
-                opcode: opcode.clone(),
+                opcode: *opcode,
                 slot,
                 goto: 0,
             },
@@ -239,7 +349,7 @@ fn parse_complex_lit<'a>(
                 r_x: reg,
                 r_y: 0,
                 r_z: 0,
-                opcode: opcode.clone(),
+                opcode: *opcode,
                 slot,
                 goto: 0,
             },
@@ -252,7 +362,7 @@ fn parse_complex_lit<'a>(
                 r_x: reg,
                 r_y: 0,
                 r_z: 0,
-                opcode: opcode.clone(),
+                opcode: *opcode,
                 slot,
                 goto: 0,
             },
@@ -265,7 +375,7 @@ fn parse_complex_lit<'a>(
                 r_x: reg,
                 r_y: 0,
                 r_z: 0,
-                opcode: opcode.clone(),
+                opcode: *opcode,
                 slot,
                 goto: 0,
             },
@@ -278,24 +388,44 @@ fn parse_complex_lit<'a>(
                 r_x: reg,
                 r_y: 0,
                 r_z: 0,
-                opcode: opcode.clone(),
+                opcode: *opcode,
                 slot,
                 goto: 0,
             },
         ))
     } else {
-        panic!("Unimplemented")
+        Err(failure(t))
+    }
+}
+
+// A string written as `string N c1 ... cN`, with each character given by its
+// code.
+fn parse_string(rest: &str) -> IResult<&str, String> {
+    let (rest, _) = nom::bytes::complete::tag("string")(rest)?;
+    parse_string_body(rest)
+}
+
+fn parse_string_body(rest: &str) -> IResult<&str, String> {
+    let (rest, _) = nom::character::complete::multispace0(rest)?;
+    let (mut rest, num) = number::<usize>(rest)?;
+    let mut s = String::new();
+    for _ in 0..num {
+        let (more, _) = nom::character::complete::multispace0(rest)?;
+        let (more, c) = number::<u8>(more)?;
+        s.push(c as char);
+        rest = more;
     }
+    Ok((rest, s))
 }
 
 fn parse_r1<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction> {
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_x) = nom::character::complete::digit1(rest)?;
+    let (rest, r_x) = number(rest)?;
     Ok((
         rest,
         Instruction {
-            opcode: opcode.clone(),
-            r_x: r_x.parse().unwrap(),
+            opcode: *opcode,
+            r_x,
             r_y: 0,
             r_z: 0,
             slot: 0,
@@ -306,20 +436,109 @@ fn parse_r1<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction
 
 fn parse_r3<'a>(opcode: &Opcodes, rest: &'a str) -> IResult<&'a str, Instruction> {
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_x) = nom::character::complete::digit1(rest)?;
+    let (rest, r_x) = number(rest)?;
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_y) = nom::character::complete::digit1(rest)?;
+    let (rest, r_y) = number(rest)?;
     let (rest, _) = nom::character::complete::multispace0(rest)?;
-    let (rest, r_z) = nom::character::complete::digit1(rest)?;
+    let (rest, r_z) = number(rest)?;
     Ok((
         rest,
         Instruction {
-            opcode: opcode.clone(),
-            r_x: r_x.parse().unwrap(),
-            r_y: r_y.parse().unwrap(),
-            r_z: r_z.parse().unwrap(),
+            opcode: *opcode,
+            r_x,
+            r_y,
+            r_z,
             slot: 0,
             goto: 0,
         },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::VMError,
+        vmrun::{run, Status},
+        vmstate::init_vm_state,
+    };
+
+    // f divides its argument by zero on line 3 of prog.scm.
+    const DIVIDE_BY_ZERO: &str = ".load module 4
+.source string 8 112 114 111 103 46 115 99 109
+.line 1 1
+.load 0 function 1 3
+.name string 1 102
+.line 3 5
+loadliteral 2 0
+/ 3 1 2
+return 3
+.line 5 1
+loadliteral 1 7
+call 2 0 1
+halt
+";
+
+    #[test]
+    fn reads_source_map_directives() {
+        let mut vm = init_vm_state();
+        let module = load_str(DIVIDE_BY_ZERO, &mut vm).unwrap();
+        let f = vm.functions().next().unwrap();
+        assert_eq!(f.name, "f");
+        assert_eq!(f.source.as_deref(), Some("prog.scm"));
+        assert_eq!(f.location(0).as_deref(), Some("prog.scm:3:5"));
+        assert_eq!(f.location(2).as_deref(), Some("prog.scm:3:5"));
+        assert_eq!(module.location(0).as_deref(), Some("prog.scm:1:1"));
+        assert_eq!(module.location(1).as_deref(), Some("prog.scm:5:1"));
+    }
+
+    #[test]
+    fn reports_errors_at_their_source_location() {
+        let mut vm = init_vm_state();
+        let module = load_str(DIVIDE_BY_ZERO, &mut vm).unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("Can't divide 7 by 0"))
+        );
+        assert_eq!(vm.location(), "f at 1 (prog.scm:3:5)");
+    }
+
+    #[test]
+    fn reports_errors_without_a_source_by_instruction() {
+        let mut vm = init_vm_state();
+        let module = load_str(".load module 2\nloadliteral 1 0\n/ 2 1 1\n", &mut vm).unwrap();
+        assert!(run(&mut vm, module).is_err());
+        assert_eq!(vm.location(), "module at 1");
+    }
+
+    #[test]
+    fn names_functions_after_the_globals_they_are_stored_in() {
+        // square and adder are stored in globals of those names.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 9
+.load 1 function 0 1 named
+return 0
+.load 1 function 1 1
+return 1
+mov 2 1
+setglobal 2 string 6 115 113 117 97 114 101
+.load 1 function 0 1
+return 0
+mkclosure 2 1 0
+setglobal 2 string 5 97 100 100 101 114
+.load 1 function 0 1
+return 0
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        let names: Vec<&str> = vm.functions().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["named", "square", "adder", "fn@5"]);
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        let global = |name: &str| vm.globals[&Val::String(name.to_string())].to_string();
+        assert_eq!(global("square"), "#<function square/1>");
+        assert_eq!(global("adder"), "#<closure adder/0>");
+    }
+}
@@ -1,30 +1,325 @@
-mod loader;
-mod opcodes;
-mod value;
-mod vmrun;
-mod vmstack;
-mod vmstate;
-use either::Either;
 use std::env;
 use std::fs;
-use std::io;
-use vmrun::run;
+use std::io::{self, IsTerminal};
+use std::process;
+use std::time::{Duration, Instant};
+use svm::coverage::Coverage;
+use svm::debugger::{Debugger, StdinLines};
+use svm::input::Script;
+use svm::output::{File, Stdout};
+use svm::primitives::Random;
+use svm::profile::Profiler;
+use svm::trace::Tracer;
+use svm::vmrun::run;
+use svm::{dap, memory, ports, repl, Status, VMError, VMFunction, VMState};
+
+#[derive(Default)]
+struct Options {
+    file: Option<String>,
+    debug: bool,
+    dap: bool,
+    repl: bool,
+    trace: bool,
+    trace_function: Option<String>,
+    trace_limit: Option<usize>,
+    profile: bool,
+    profile_stacks: Option<String>,
+    coverage: bool,
+    coverage_listing: Option<String>,
+    coverage_lcov: Option<String>,
+    quantum: Option<usize>,
+    max_depth: Option<usize>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    max_memory: Option<usize>,
+    memory_stats: bool,
+    output: Option<String>,
+    no_color: bool,
+    allow_fs: Option<String>,
+    seed: Option<u64>,
+    // The arguments after the program file, which are the program's own.
+    args: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: svm [options] [file.vo [arguments...]]");
+    eprintln!("       svm debug file.vo [arguments...]");
+    eprintln!("       svm repl [options]");
+    eprintln!("       svm dap");
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("  --trace                 print every instruction executed");
+    eprintln!("  --trace-function=NAME   only trace instructions of function NAME");
+    eprintln!("  --trace-limit=N         stop tracing after N lines");
+    eprintln!("  --profile               report instruction counts and time per function");
+    eprintln!("  --profile-stacks=FILE   also write collapsed call stacks for flamegraphs");
+    eprintln!("  --coverage              report the instructions executed in each function");
+    eprintln!("  --coverage-listing=FILE also write a disassembly annotated with counts");
+    eprintln!("  --coverage-lcov=FILE    also write line coverage of the source in lcov format");
+    eprintln!("  --fuel=N                stop after executing N instructions");
+    eprintln!("  --timeout=SECONDS       stop after running for SECONDS");
+    eprintln!(
+        "  --max-memory=SIZE       fail when the heap grows past SIZE bytes (K, M or G suffix)"
+    );
+    eprintln!("  --memory-stats          report the peak heap size");
+    eprintln!("  --max-depth=N           allow at most N nested calls (default 10000)");
+    eprintln!("  --quantum=N             switch threads every N instructions (default 100)");
+    eprintln!("  --output=FILE           write what the program prints to FILE");
+    eprintln!("  --no-color              never color the test results");
+    eprintln!("  --allow-fs=DIR          let the program open files under DIR");
+    eprintln!("  --seed=N                seed the random numbers with N");
+    process::exit(2)
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options::default();
+    let args = match args {
+        [debug, rest @ ..] if debug == "debug" => {
+            options.debug = true;
+            rest
+        }
+        [repl, rest @ ..] if repl == "repl" => {
+            options.repl = true;
+            rest
+        }
+        [dap] if dap == "dap" => {
+            options.dap = true;
+            &[]
+        }
+        _ => args,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--trace" {
+            options.trace = true;
+        } else if let Some(name) = arg.strip_prefix("--trace-function=") {
+            options.trace = true;
+            options.trace_function = Some(name.to_string());
+        } else if let Some(n) = arg.strip_prefix("--trace-limit=") {
+            options.trace = true;
+            options.trace_limit = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if arg == "--profile" {
+            options.profile = true;
+        } else if let Some(path) = arg.strip_prefix("--profile-stacks=") {
+            options.profile = true;
+            options.profile_stacks = Some(path.to_string());
+        } else if arg == "--coverage" {
+            options.coverage = true;
+        } else if let Some(path) = arg.strip_prefix("--coverage-listing=") {
+            options.coverage = true;
+            options.coverage_listing = Some(path.to_string());
+        } else if let Some(path) = arg.strip_prefix("--coverage-lcov=") {
+            options.coverage = true;
+            options.coverage_lcov = Some(path.to_string());
+        } else if let Some(n) = arg.strip_prefix("--fuel=") {
+            options.fuel = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(n) = arg.strip_prefix("--timeout=") {
+            let timeout = n
+                .parse()
+                .ok()
+                .and_then(|n| Duration::try_from_secs_f64(n).ok());
+            options.timeout = Some(timeout.unwrap_or_else(|| usage()));
+        } else if let Some(size) = arg.strip_prefix("--max-memory=") {
+            options.max_memory = Some(memory::parse_size(size).unwrap_or_else(|| usage()));
+        } else if arg == "--memory-stats" {
+            options.memory_stats = true;
+        } else if let Some(n) = arg.strip_prefix("--max-depth=") {
+            options.max_depth = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(n) = arg.strip_prefix("--quantum=") {
+            match n.parse() {
+                Ok(n) if n > 0 => options.quantum = Some(n),
+                _ => usage(),
+            }
+        } else if let Some(path) = arg.strip_prefix("--output=") {
+            options.output = Some(path.to_string());
+        } else if arg == "--no-color" {
+            options.no_color = true;
+        } else if let Some(dir) = arg.strip_prefix("--allow-fs=") {
+            options.allow_fs = Some(dir.to_string());
+        } else if let Some(n) = arg.strip_prefix("--seed=") {
+            options.seed = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if arg.starts_with("--") {
+            usage()
+        } else {
+            options.file = Some(arg.clone());
+            options.args = args.cloned().collect();
+            break;
+        }
+    }
+    if options.debug && options.file.is_none() || options.repl && options.file.is_some() {
+        usage()
+    }
+    options
+}
 
-use vmstate::init_vm_state;
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let mut state = init_vm_state();
-    let parser_map = opcodes::get_parsers();
-    if args.len() == 1 {
-        let mut vm_function =
-            loader::load_modules(Either::Left(io::stdin()), &parser_map, &mut state);
-        run(&mut state, &mut vm_function);
+    let options = parse_args(&args[1..]);
+    if options.dap {
+        dap::serve_stdio().expect("Debug adapter failed");
+        return;
+    }
+    let mut state = VMState::new();
+    if options.trace {
+        state.tracer = Some(Tracer::new(
+            options.trace_function.clone(),
+            options.trace_limit,
+        ));
+    }
+    state.fuel = options.fuel;
+    if let Some(seed) = options.seed {
+        state.random = Random::new(seed);
+    }
+    state.args = options.args.clone();
+    state.memory.limit = options.max_memory;
+    if let Some(max_depth) = options.max_depth {
+        state.max_depth = max_depth;
+    }
+    if let Some(quantum) = options.quantum {
+        state.quantum = quantum;
+    }
+    if let Some(path) = &options.output {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("error creating {}: {}", path, e);
+            process::exit(1)
+        });
+        state.output = Box::new(file);
+    } else if options.no_color {
+        state.output = Box::new(Stdout::with_color(false));
+    }
+    if let Some(dir) = &options.allow_fs {
+        state.allow_fs(dir).unwrap_or_else(|e| {
+            eprintln!("error opening {}: {}", dir, e);
+            process::exit(1)
+        });
+    }
+    if options.profile {
+        state.profiler = Some(Profiler::new());
+    }
+    if options.coverage {
+        state.coverage = Some(Coverage::new());
+    }
+    if options.repl {
+        // stdin carries the modules, so they get no input of their own.
+        state.input = Box::new(Script::new(""));
+        let stdin = io::stdin();
+        let prompts = stdin.is_terminal();
+        let modules =
+            repl::run(&mut state, stdin.lock(), prompts, options.timeout).unwrap_or_else(|e| {
+                eprintln!("error reading stdin: {}", e);
+                process::exit(1)
+            });
+        report_stats(&mut state, &options, &modules.iter().collect::<Vec<_>>());
+        report_tests(&mut state);
+        return;
+    }
+    let loaded = match &options.file {
+        // The program takes up stdin, so it has no input of its own.
+        None => {
+            state.input = Box::new(Script::new(""));
+            state.load_reader(io::stdin())
+        }
+        Some(path) => {
+            let my_file = fs::File::open(path).expect("Failed to open file");
+            state.load_reader(my_file)
+        }
+    };
+    let vm_function = loaded.unwrap_or_else(|e| {
+        let file = options.file.as_deref().unwrap_or("stdin");
+        eprintln!("error loading {}: {}", file, e);
+        process::exit(1)
+    });
+    let module = vm_function.clone();
+    if let Some(timeout) = options.timeout {
+        state.deadline = Some(
+            Instant::now()
+                .checked_add(timeout)
+                .unwrap_or_else(|| usage()),
+        );
+    }
+    let result = if options.debug {
+        Debugger::new()
+            .run(&mut state, vm_function, StdinLines::default())
+            .expect("Failed to run the debugger");
+        Ok(Status::Halted)
     } else {
-        let my_file = fs::File::open(args.get(1).unwrap()).expect("Failed to open file");
+        run(&mut state, vm_function)
+    };
+    report_stats(&mut state, &options, &[&module]);
+    let result = match result {
+        Ok(Status::OutOfFuel) => Err(VMError::runtime("out of fuel")),
+        Ok(Status::TimedOut) => Err(VMError::runtime(format!(
+            "timed out after {} seconds",
+            options.timeout.unwrap_or_default().as_secs_f64()
+        ))),
+        result => result,
+    };
+    if let Err(e) = result {
+        eprintln!("error in {}: {}", state.location(), e);
+        if !state.stack.is_empty() {
+            for line in state.backtrace() {
+                eprintln!("  {}", line);
+            }
+        }
+        report_tests(&mut state);
+        process::exit(1);
+    }
+    report_tests(&mut state);
+}
+
+// Writes the profile, coverage and memory reports asked for. `modules` are
+// the top-level functions run, which the VM doesn't keep.
+fn report_stats(state: &mut VMState, options: &Options, modules: &[&VMFunction]) {
+    if let Some(profiler) = state.profiler.as_mut() {
+        profiler.finish();
+    }
+    let mut functions = modules.to_vec();
+    functions.extend(state.functions());
+    if let Some(profiler) = &state.profiler {
+        profiler
+            .report(&functions, &mut io::stderr())
+            .expect("Failed to write profile");
+        if let Some(path) = &options.profile_stacks {
+            let mut file = fs::File::create(path).expect("Failed to create stacks file");
+            profiler
+                .write_stacks(&mut file)
+                .expect("Failed to write stacks file");
+        }
+    }
+    if let Some(coverage) = &state.coverage {
+        coverage
+            .report(&functions, &mut io::stderr())
+            .expect("Failed to write coverage report");
+        if let Some(path) = &options.coverage_listing {
+            let mut file = fs::File::create(path).expect("Failed to create coverage listing");
+            coverage
+                .write_listing(&functions, &mut file)
+                .expect("Failed to write coverage listing");
+        }
+        if let Some(path) = &options.coverage_lcov {
+            let mut file = fs::File::create(path).expect("Failed to create lcov file");
+            coverage
+                .write_lcov(&functions, &mut file)
+                .expect("Failed to write lcov file");
+        }
+    }
+    if options.memory_stats {
+        eprintln!("peak memory: {} bytes", state.memory.peak);
+    }
+}
 
-        let mut vm_function = loader::load_modules(Either::Right(my_file), &parser_map, &mut state);
-        run(&mut state, &mut vm_function);
+// Reports the test results after what the program printed, and makes sure
+// both, and whatever it wrote to files, are written out before the process
+// exits.
+fn report_tests(state: &mut VMState) {
+    let reported = state
+        .test_suite
+        .report_tests(&mut *state.output)
+        .and_then(|_| state.output.flush())
+        .map_err(VMError::from)
+        .and_then(|_| ports::close_all(state));
+    if let Err(e) = reported {
+        eprintln!("error writing output: {}", e);
+        process::exit(1);
     }
-    state.test_suite.report_tests();
-    ()
 }
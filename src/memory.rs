@@ -0,0 +1,142 @@
+use std::mem::size_of;
+
+use crate::error::VMError;
+use crate::value::value::Val;
+use crate::vmstate::VMState;
+
+// Scans are not worth doing until at least this much has been allocated.
+const MIN_SCAN: usize = 1 << 20;
+
+// Heap accounting. Instructions that allocate report how many bytes they
+// need, which are added to an estimate of what is in use. Nothing reports
+// memory being freed, so when the estimate passes the limit, or doubles
+// since the last scan, it is replaced by the size of everything reachable
+// from the VM. Between scans the estimate can only be too high, so the
+// peak it reports is an upper bound.
+//
+// The register files need no limit of their own: they never grow. The main
+// program's is allocated once with the VM, each coroutine and thread gets
+// one of `TASK_REGISTERS` that is charged when it is created, and a call
+// whose window would run past the end of its file is a stack overflow.
+#[derive(Debug)]
+pub struct Memory {
+    pub limit: Option<usize>,
+    pub in_use: usize,
+    pub peak: usize,
+    next_scan: usize,
+}
+
+impl Memory {
+    pub fn new(limit: Option<usize>) -> Self {
+        Memory {
+            limit,
+            in_use: 0,
+            peak: 0,
+            next_scan: MIN_SCAN,
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new(None)
+    }
+}
+
+pub fn allocate(vm: &mut VMState, bytes: usize) -> Result<(), VMError> {
+    vm.memory.in_use += bytes;
+    let over_limit = vm
+        .memory
+        .limit
+        .is_some_and(|limit| vm.memory.in_use > limit);
+    if over_limit || vm.memory.in_use > vm.memory.next_scan {
+        vm.memory.in_use = live_size(vm) + bytes;
+        vm.memory.next_scan = (2 * vm.memory.in_use).max(MIN_SCAN);
+    }
+    if let Some(limit) = vm.memory.limit {
+        if vm.memory.in_use > limit {
+            let needed = vm.memory.in_use;
+            vm.memory.in_use -= bytes;
+            return Err(VMError::runtime(format!(
+                "out of memory: {} bytes needed, the limit is {}",
+                needed, limit
+            )));
+        }
+    }
+    vm.memory.peak = vm.memory.peak.max(vm.memory.in_use);
+    Ok(())
+}
+
+// The size of a number of registers or closure slots.
+pub fn slots(n: usize) -> usize {
+    n * size_of::<Val>()
+}
+
+// The bytes of heap reachable from the registers, globals, channels and saved
+// contexts of coroutines and threads. The registers of the main program are
+// there from the start, so only what they point to counts.
+fn live_size(vm: &VMState) -> usize {
+    let mut size = 0;
+    let mut pending: Vec<&Val> = vm.registers.iter().collect();
+    pending.extend(vm.globals.values());
+    for channel in vm.channels.iter() {
+        size += slots(channel.len());
+        pending.extend(channel.iter());
+    }
+    let contexts = vm
+        .coroutines
+        .iter()
+        .map(|co| &co.context)
+        .chain(vm.threads.iter().map(|thread| &thread.context));
+    for context in contexts {
+        size += slots(context.registers.len());
+        pending.extend(context.registers.iter());
+    }
+    size + reachable_size(pending)
+}
+
+// The bytes of heap a value owns. Values are copied, not shared, when they
+// are stored, so this is also what storing one more copy of it costs.
+pub fn heap_size(v: &Val) -> usize {
+    match v {
+        Val::Cons(..) | Val::String(_) | Val::Closure(..) | Val::Continuation(_) => {
+            reachable_size(vec![v])
+        }
+        _ => 0,
+    }
+}
+
+fn reachable_size(mut pending: Vec<&Val>) -> usize {
+    let mut size = 0;
+    while let Some(v) = pending.pop() {
+        match v {
+            Val::Cons(x, xs) => {
+                size += slots(2);
+                pending.push(x);
+                pending.push(xs);
+            }
+            Val::String(s) => size += s.capacity(),
+            Val::Closure(_, captured) => {
+                size += slots(captured.len());
+                pending.extend(captured.iter());
+            }
+            Val::Continuation(k) => {
+                size += slots(k.registers.len());
+                pending.extend(k.registers.iter());
+            }
+            _ => {}
+        }
+    }
+    size
+}
+
+// A size in bytes, optionally with a K, M or G suffix.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 1 << 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
+use std::fmt::{self, Display};
 
-const INSTRUCTIONS: [(&'static str, &'static InstructionParser, Opcodes); 40] = [
+const INSTRUCTIONS: [(&str, &InstructionParser, Opcodes); 61] = [
     (
         "loadliteral",
         &InstructionParser::R1Lit,
@@ -33,6 +34,35 @@ const INSTRUCTIONS: [(&'static str, &'static InstructionParser, Opcodes); 40] =
     ("<=", &InstructionParser::R3, Opcodes::LessEq),
     ("call", &InstructionParser::R3, Opcodes::Call),
     ("tailcall", &InstructionParser::R2, Opcodes::TailCall),
+    ("apply", &InstructionParser::R3, Opcodes::Apply),
+    ("callcc", &InstructionParser::R2, Opcodes::CallCC),
+    ("coroutine", &InstructionParser::R2, Opcodes::MakeCoroutine),
+    ("resume", &InstructionParser::R3, Opcodes::Resume),
+    ("yield", &InstructionParser::R2, Opcodes::Yield),
+    ("done?", &InstructionParser::R2, Opcodes::IsDone),
+    ("spawn", &InstructionParser::R2, Opcodes::Spawn),
+    ("channel", &InstructionParser::R1, Opcodes::MakeChannel),
+    ("send", &InstructionParser::R2, Opcodes::Send),
+    ("receive", &InstructionParser::R2, Opcodes::Receive),
+    ("read-line", &InstructionParser::R1, Opcodes::ReadLine),
+    ("read-number", &InstructionParser::R1, Opcodes::ReadNumber),
+    ("read", &InstructionParser::R1, Opcodes::Read),
+    ("eof?", &InstructionParser::R2, Opcodes::IsEof),
+    ("open-input", &InstructionParser::R2, Opcodes::OpenInput),
+    ("open-output", &InstructionParser::R2, Opcodes::OpenOutput),
+    ("close-port", &InstructionParser::R1, Opcodes::ClosePort),
+    (
+        "port-read-line",
+        &InstructionParser::R2,
+        Opcodes::PortReadLine,
+    ),
+    ("port-read", &InstructionParser::R2, Opcodes::PortRead),
+    ("port-write", &InstructionParser::R2, Opcodes::PortWrite),
+    (
+        "port-write-line",
+        &InstructionParser::R2,
+        Opcodes::PortWriteLine,
+    ),
     ("return", &InstructionParser::R1, Opcodes::Return),
     ("cons", &InstructionParser::R3, Opcodes::Cons),
     ("car", &InstructionParser::R2, Opcodes::Car),
@@ -56,7 +86,7 @@ pub enum InstructionParser {
     R1Lit,
     R0I24,
 }
-#[derive(Debug, Clone, Copy, Hash)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum Opcodes {
     Add,
     LoadLiteral,
@@ -86,6 +116,27 @@ pub enum Opcodes {
     Return,
     Call,
     TailCall,
+    Apply,
+    CallCC,
+    MakeCoroutine,
+    Resume,
+    Yield,
+    IsDone,
+    Spawn,
+    MakeChannel,
+    Send,
+    Receive,
+    ReadLine,
+    ReadNumber,
+    Read,
+    IsEof,
+    OpenInput,
+    OpenOutput,
+    ClosePort,
+    PortReadLine,
+    PortRead,
+    PortWrite,
+    PortWriteLine,
     Cons,
     Car,
     Cdr,
@@ -122,6 +173,39 @@ impl Instruction {
     }
 }
 
+impl Opcodes {
+    pub fn mnemonic(&self) -> &'static str {
+        INSTRUCTIONS
+            .iter()
+            .find(|(_, _, op)| op == self)
+            .map(|(name, _, _)| *name)
+            .unwrap()
+    }
+    pub fn parser(&self) -> InstructionParser {
+        INSTRUCTIONS
+            .iter()
+            .find(|(_, _, op)| op == self)
+            .map(|(_, parser, _)| **parser)
+            .unwrap()
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.opcode.mnemonic();
+        match self.opcode.parser() {
+            InstructionParser::R3 => {
+                write!(f, "{} r{} r{} r{}", name, self.r_x, self.r_y, self.r_z)
+            }
+            InstructionParser::R2 => write!(f, "{} r{} r{}", name, self.r_x, self.r_y),
+            InstructionParser::R1 => write!(f, "{} r{}", name, self.r_x),
+            InstructionParser::R0 => write!(f, "{}", name),
+            InstructionParser::R1Lit => write!(f, "{} r{} lit{}", name, self.r_x, self.slot),
+            InstructionParser::R0I24 => write!(f, "{} {:+}", name, self.goto),
+        }
+    }
+}
+
 pub fn get_parsers() -> HashMap<String, (InstructionParser, Opcodes)> {
     let mut map = HashMap::new();
     for (opcode, ptype, op) in INSTRUCTIONS.iter() {
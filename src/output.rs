@@ -0,0 +1,99 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// Where a VM sends what the program prints and the results of its tests.
+pub trait Output: Write {
+    // Whether what is written here may be colored with terminal escapes.
+    fn color(&self) -> bool {
+        false
+    }
+}
+
+// Standard output, colored unless it is redirected or told otherwise.
+pub struct Stdout {
+    color: bool,
+}
+
+impl Stdout {
+    pub fn new() -> Self {
+        Stdout::with_color(io::stdout().is_terminal())
+    }
+    pub fn with_color(color: bool) -> Self {
+        Stdout { color }
+    }
+}
+
+impl Default for Stdout {
+    fn default() -> Self {
+        Stdout::new()
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Output for Stdout {
+    fn color(&self) -> bool {
+        self.color
+    }
+}
+
+// Output kept in memory. Clones share the same contents, so one can be given
+// to the VM and another kept to read what was printed.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer::default()
+    }
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+    // Returns the contents and empties the buffer.
+    pub fn take(&self) -> String {
+        let bytes: Vec<u8> = self.0.borrow_mut().drain(..).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output for Buffer {}
+
+// Output written to a file, which is created or truncated.
+pub struct File(BufWriter<fs::File>);
+
+impl File {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(File(BufWriter::new(fs::File::create(path)?)))
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Output for File {}
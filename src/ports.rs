@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::VMError;
+use crate::input::{self, Input, Reading};
+use crate::value::value::Val;
+use crate::vmstate::VMState;
+
+// How many ports a program may have open at once.
+const MAX_PORTS: usize = 64;
+
+// A file opened by a program, for reading or for writing.
+pub enum Port {
+    Input { file: FileInput, unread: String },
+    Output(BufWriter<fs::File>),
+}
+
+// A file read a line at a time, as the VM's input is.
+pub struct FileInput(BufReader<fs::File>);
+
+impl Input for FileInput {
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match self.0.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+}
+
+// Opens the file named by `path` and returns its port. Programs may only
+// open files under `vm.fs_root`, and none at all when it isn't set; relative
+// paths are taken from there. The port gets the slot of a closed one, if
+// there is one.
+pub fn open(vm: &mut VMState, path: &Val, writing: bool) -> Result<Val, VMError> {
+    let path = match path {
+        Val::String(path) => path,
+        _ => {
+            return Err(VMError::runtime(format!(
+                "Can't open {}, which isn't a file name",
+                path
+            )))
+        }
+    };
+    let refuse = |reason: String| VMError::runtime(format!("Can't open {}: {}", path, reason));
+    let root = vm
+        .fs_root
+        .as_ref()
+        .ok_or_else(|| refuse("file access is disabled (see --allow-fs)".to_string()))?;
+    let resolved = resolve(&root.join(path), writing).map_err(|e| refuse(e.to_string()))?;
+    if !resolved.starts_with(root) {
+        return Err(refuse(format!("it is outside {}", root.display())));
+    }
+    let slot = vm.ports.iter().position(Option::is_none);
+    if slot.is_none() && vm.ports.len() >= MAX_PORTS {
+        return Err(refuse(format!("{} files are open already", MAX_PORTS)));
+    }
+    let port = if writing {
+        let file = fs::File::create(&resolved).map_err(|e| refuse(e.to_string()))?;
+        Port::Output(BufWriter::new(file))
+    } else {
+        let file = fs::File::open(&resolved).map_err(|e| refuse(e.to_string()))?;
+        Port::Input {
+            file: FileInput(BufReader::new(file)),
+            unread: String::new(),
+        }
+    };
+    match slot {
+        Some(n) => {
+            vm.ports[n] = Some(port);
+            Ok(Val::Port(n))
+        }
+        None => {
+            vm.ports.push(Some(port));
+            Ok(Val::Port(vm.ports.len() - 1))
+        }
+    }
+}
+
+// Where `path` really is, with `..` and symbolic links resolved, so that
+// checking it is under the root can't be fooled. A file about to be created
+// doesn't exist yet, so its directory is resolved instead.
+fn resolve(path: &Path, writing: bool) -> io::Result<PathBuf> {
+    if !writing || fs::symlink_metadata(path).is_ok() {
+        return fs::canonicalize(path);
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(directory)?.join(name))
+}
+
+// Closes a port, writing out what was written to it. Closing a port again
+// does nothing.
+pub fn close(vm: &mut VMState, port: &Val) -> Result<(), VMError> {
+    let n = index(vm, port, "close")?;
+    if let Some(Port::Output(mut file)) = vm.ports[n].take() {
+        file.flush()?;
+    }
+    Ok(())
+}
+
+// Reads from a port opened for reading, as the input opcodes read from the
+// VM's input.
+pub fn read(vm: &mut VMState, port: &Val, reading: Reading) -> Result<Option<Val>, VMError> {
+    let n = index(vm, port, "read from")?;
+    match &mut vm.ports[n] {
+        Some(Port::Input { file, unread }) => input::read(file, unread, reading),
+        Some(Port::Output(_)) => Err(VMError::runtime(format!(
+            "Can't read from {}, which is open for writing",
+            port
+        ))),
+        None => Err(VMError::runtime(format!(
+            "Can't read from {}, which is closed",
+            port
+        ))),
+    }
+}
+
+// Writes a value to a port opened for writing, as `print` would show it.
+pub fn write(vm: &mut VMState, port: &Val, v: &Val, newline: bool) -> Result<(), VMError> {
+    let n = index(vm, port, "write to")?;
+    match &mut vm.ports[n] {
+        Some(Port::Output(file)) if newline => writeln!(file, "{}", v)?,
+        Some(Port::Output(file)) => write!(file, "{}", v)?,
+        Some(Port::Input { .. }) => {
+            return Err(VMError::runtime(format!(
+                "Can't write to {}, which is open for reading",
+                port
+            )))
+        }
+        None => {
+            return Err(VMError::runtime(format!(
+                "Can't write to {}, which is closed",
+                port
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn index(vm: &VMState, port: &Val, action: &str) -> Result<usize, VMError> {
+    match port {
+        Val::Port(n) if *n < vm.ports.len() => Ok(*n),
+        _ => Err(VMError::runtime(format!(
+            "Can't {} {}, which isn't a port",
+            action, port
+        ))),
+    }
+}
+
+// Closes every port, for when the program is over.
+pub fn close_all(vm: &mut VMState) -> Result<(), VMError> {
+    for port in vm.ports.iter_mut() {
+        if let Some(Port::Output(mut file)) = port.take() {
+            file.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::convert::{FromVal, IntoVal};
+use crate::error::VMError;
+use crate::vmstate::VMState;
+
+// Primitives every VM starts with:
+//
+//     (clock-ms)                 milliseconds since the VM was created
+//     (random n)                 a number from 0 to n - 1
+//     (command-line-arguments)   the arguments after the program file
+pub fn define_primitives(vm: &mut VMState) {
+    vm.define_native_with_vm("clock-ms", 0, |vm, _| {
+        let elapsed = vm.clock.elapsed().as_millis();
+        Ok(i32::try_from(elapsed).unwrap_or(i32::MAX).into_val())
+    });
+    vm.define_native_with_vm("random", 1, |vm, args| {
+        let n = i32::from_val(&args[0])?;
+        if n <= 0 {
+            return Err(VMError::runtime(format!(
+                "random expects a positive number, got {}",
+                n
+            )));
+        }
+        Ok(((vm.random.next_u64() % n as u64) as i32).into_val())
+    });
+    vm.define_native_with_vm("command-line-arguments", 0, |vm, _| {
+        Ok(vm.args.clone().into_val())
+    });
+}
+
+// A pseudo-random number generator (SplitMix64). The same seed always gives
+// the same numbers.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+    // A generator seeded from the time, for when runs needn't be repeatable.
+    pub fn from_time() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Random::new(now)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::opcodes::Opcodes;
+use crate::value::value::VMFunction;
+
+#[derive(Debug, Default)]
+pub struct FunctionStats {
+    pub calls: u64,
+    pub self_instructions: u64,
+    pub total_instructions: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+#[derive(Debug)]
+struct Frame {
+    function: String,
+    instructions: u64,
+    start: Instant,
+}
+
+// Counts instructions per opcode and per function. `vmrun` reports every
+// instruction before executing it and every call, tail call and return after
+// executing it; the profiler keeps a shadow of the call stack to attribute
+// costs, flushing the instructions counted since the last call or return to
+// the frame on top of it.
+#[derive(Debug)]
+pub struct Profiler {
+    pub opcodes: HashMap<String, u64>,
+    pub functions: HashMap<String, FunctionStats>,
+    pub stacks: HashMap<String, u64>,
+    frames: Vec<Frame>,
+    instructions: u64,
+    pending: u64,
+    last_event: Instant,
+    started: Instant,
+    elapsed: Duration,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Profiler {
+            opcodes: HashMap::new(),
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: Vec::new(),
+            instructions: 0,
+            pending: 0,
+            last_event: now,
+            started: now,
+            elapsed: Duration::default(),
+        }
+    }
+
+    pub fn instruction(&mut self, opcode: Opcodes, function: &str) {
+        if self.frames.is_empty() {
+            self.started = Instant::now();
+            self.last_event = self.started;
+            self.enter(function);
+        }
+        *self
+            .opcodes
+            .entry(opcode.mnemonic().to_string())
+            .or_insert(0) += 1;
+        self.instructions += 1;
+        self.pending += 1;
+    }
+
+    pub fn after(&mut self, opcode: Opcodes, function: &str) {
+        match opcode {
+            Opcodes::Call | Opcodes::Apply | Opcodes::CallCC => self.enter(function),
+            Opcodes::TailCall => {
+                self.exit();
+                self.enter(function);
+            }
+            Opcodes::Return => self.exit(),
+            _ => {}
+        }
+    }
+
+    // Brings the shadow stack back in line with the VM's after control jumps
+    // to another stack, as invoking a continuation or switching coroutines
+    // does. `functions` runs from the bottom of the new stack to the function
+    // now running; frames no longer on it are closed and the missing ones
+    // opened without counting them as calls.
+    pub fn sync<'a>(&mut self, functions: impl IntoIterator<Item = &'a str>) {
+        let functions: Vec<&str> = functions.into_iter().collect();
+        let kept = self
+            .frames
+            .iter()
+            .zip(&functions)
+            .take_while(|(frame, function)| frame.function == **function)
+            .count();
+        while self.frames.len() > kept {
+            self.exit();
+        }
+        self.flush();
+        for function in &functions[kept..] {
+            self.open(function);
+        }
+    }
+
+    // Closes the frames still open when the program halts.
+    pub fn finish(&mut self) {
+        while !self.frames.is_empty() {
+            self.exit();
+        }
+        self.elapsed = self.started.elapsed();
+    }
+
+    fn flush(&mut self) {
+        let now = Instant::now();
+        if let Some(top) = self.frames.last() {
+            let path: Vec<&str> = self.frames.iter().map(|f| f.function.as_str()).collect();
+            *self.stacks.entry(path.join(";")).or_insert(0) += self.pending;
+            let stats = self.functions.entry(top.function.clone()).or_default();
+            stats.self_instructions += self.pending;
+            stats.self_time += now - self.last_event;
+        }
+        self.pending = 0;
+        self.last_event = now;
+    }
+
+    fn enter(&mut self, function: &str) {
+        self.flush();
+        self.functions
+            .entry(function.to_string())
+            .or_default()
+            .calls += 1;
+        self.open(function);
+    }
+
+    fn open(&mut self, function: &str) {
+        self.frames.push(Frame {
+            function: function.to_string(),
+            instructions: self.instructions,
+            start: self.last_event,
+        });
+    }
+
+    fn exit(&mut self) {
+        self.flush();
+        if let Some(frame) = self.frames.pop() {
+            // Recursive activations are already included in the outermost
+            // one's total.
+            if self.frames.iter().any(|f| f.function == frame.function) {
+                return;
+            }
+            let stats = self.functions.entry(frame.function).or_default();
+            stats.total_instructions += self.instructions - frame.instructions;
+            stats.total_time += self.last_event - frame.start;
+        }
+    }
+
+    pub fn report(&self, functions: &[&VMFunction], out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} instructions in {:.3} ms",
+            self.instructions,
+            millis(self.elapsed)
+        )?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<24} {:>10} {:>12} {:>12} {:>10} {:>10}  source",
+            "function", "calls", "self", "total", "self ms", "total ms"
+        )?;
+        let location = |name: &str| {
+            let function = functions.iter().find(|f| f.name == name)?;
+            let line = function.first_line()?;
+            Some(format!(
+                "{}:{}",
+                function.source.as_deref().unwrap_or("?"),
+                line
+            ))
+        };
+        let mut stats: Vec<(&String, &FunctionStats)> = self.functions.iter().collect();
+        stats.sort_by(|a, b| {
+            (b.1.self_instructions, b.1.total_instructions, a.0).cmp(&(
+                a.1.self_instructions,
+                a.1.total_instructions,
+                b.0,
+            ))
+        });
+        for (name, stats) in stats {
+            let line = format!(
+                "{:<24} {:>10} {:>12} {:>12} {:>10.3} {:>10.3}  {}",
+                name,
+                stats.calls,
+                stats.self_instructions,
+                stats.total_instructions,
+                millis(stats.self_time),
+                millis(stats.total_time),
+                location(name).unwrap_or_default()
+            );
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:<24} {:>10}", "opcode", "count")?;
+        let mut opcodes: Vec<(&String, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+        for (name, count) in opcodes {
+            writeln!(out, "{:<24} {:>10}", name, count)?;
+        }
+        Ok(())
+    }
+
+    // One line per distinct call stack with the instructions executed in
+    // it, the "collapsed" format read by flamegraph tools.
+    pub fn write_stacks(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            if *count > 0 {
+                writeln!(out, "{} {}", stack, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        loader::load_str,
+        vmrun::{run, Status},
+        vmstate::init_vm_state,
+    };
+
+    // Runs `source` with a profiler and returns it once the program is over.
+    fn profile(source: &str) -> Profiler {
+        let mut vm = init_vm_state();
+        vm.profiler = Some(Profiler::new());
+        let module = load_str(source, &mut vm).unwrap();
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        let mut profiler = vm.profiler.take().unwrap();
+        profiler.finish();
+        profiler
+    }
+
+    #[test]
+    fn counts_calls_and_instructions() {
+        let profiler = profile(
+            ".load module 6
+.load 0 function 1 2
++ 2 1 1
+return 2
+loadliteral 1 3
+call 2 0 1
+mov 1 2
+call 3 0 1
+halt
+",
+        );
+        assert_eq!(profiler.instructions, 10);
+        assert_eq!(profiler.opcodes["call"], 2);
+        assert_eq!(profiler.opcodes["+"], 2);
+        let f = &profiler.functions["fn@0"];
+        assert_eq!(
+            (f.calls, f.self_instructions, f.total_instructions),
+            (2, 4, 4)
+        );
+        let module = &profiler.functions["module"];
+        assert_eq!(
+            (
+                module.calls,
+                module.self_instructions,
+                module.total_instructions
+            ),
+            (1, 6, 10)
+        );
+    }
+
+    #[test]
+    fn writes_collapsed_stacks() {
+        // g calls f, then tail calls it.
+        let profiler = profile(
+            ".load module 5
+.load 0 function 1 2
++ 2 1 1
+return 2
+setglobal 0 string 1 102
+.load 0 function 1 6
+getglobal 2 string 1 102
+mov 3 1
+call 4 2 3
+getglobal 2 string 1 102
+mov 3 4
+tailcall 2 3
+loadliteral 1 3
+call 2 0 1
+",
+        );
+        let mut stacks = Vec::new();
+        profiler.write_stacks(&mut stacks).unwrap();
+        assert_eq!(
+            String::from_utf8(stacks).unwrap(),
+            "module 5\nmodule;f 2\nmodule;fn@4 6\nmodule;fn@4;f 2\n"
+        );
+        assert_eq!(profiler.functions["f"].calls, 2);
+        assert_eq!(profiler.functions["fn@4"].total_instructions, 8);
+    }
+
+    #[test]
+    fn follows_continuations_out_of_calls() {
+        // escape returns to the module by calling its continuation.
+        let profiler = profile(
+            ".load module 3
+.load 0 function 1 3 escape
+loadliteral 2 42
+call 3 1 2
+return 3
+callcc 1 0
+halt
+",
+        );
+        assert_eq!(profiler.functions["escape"].self_instructions, 2);
+        assert_eq!(profiler.functions["module"].self_instructions, 3);
+    }
+
+    #[test]
+    fn follows_coroutines() {
+        // once yields 7, then returns what it is resumed with.
+        let profiler = profile(
+            ".load module 5
+.load 0 function 1 3 once
+loadliteral 2 7
+yield 3 2
+return 3
+coroutine 1 0
+resume 2 1 0
+resume 3 1 0
+halt
+",
+        );
+        assert_eq!(profiler.functions["once"].self_instructions, 3);
+        assert_eq!(profiler.functions["module"].self_instructions, 5);
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{self, BufRead};
+use std::time::{Duration, Instant};
+
+use crate::opcodes::Opcodes;
+use crate::value::value::{VMFunction, Val};
+use crate::vmrun::Status;
+use crate::vmstate::VMState;
+
+// Reads modules from `input` one `.load module` at a time and runs each one
+// as soon as it is complete, in the same VM, so globals and literals carry
+// over from one to the next. A module that ends with `return` at the top
+// level has the value it returns printed; one that ends with `halt` prints
+// only what it prints itself. Errors are printed too, and don't end the
+// session. Each module gets `timeout` to run in, if given. Returns the
+// modules run, for reports on the session.
+pub fn run(
+    vm: &mut VMState,
+    input: impl BufRead,
+    prompts: bool,
+    timeout: Option<Duration>,
+) -> io::Result<Vec<VMFunction>> {
+    let mut modules = Vec::new();
+    let mut chunk = Chunk::default();
+    let mut lines = input.lines();
+    loop {
+        if prompts {
+            let prompt = if chunk.started() { "...> " } else { "svm> " };
+            write!(vm.output, "{}", prompt)?;
+            vm.output.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        match chunk.add(&line) {
+            Ok(false) => {}
+            Ok(true) => {
+                let text = std::mem::take(&mut chunk).text;
+                match vm.load_str(&text) {
+                    Ok(module) => {
+                        modules.push(module.clone());
+                        vm.deadline =
+                            timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+                        evaluate(vm, module)?;
+                    }
+                    Err(e) => writeln!(vm.output, "error loading module: {}", e)?,
+                }
+            }
+            Err(message) => {
+                chunk = Chunk::default();
+                writeln!(vm.output, "error: {}", message)?;
+            }
+        }
+        vm.output.flush()?;
+    }
+    if chunk.started() {
+        writeln!(vm.output, "error: the input ends inside a module")?;
+    }
+    Ok(modules)
+}
+
+fn evaluate(vm: &mut VMState, module: VMFunction) -> io::Result<()> {
+    vm.start(module);
+    let result = if returning(vm).is_some() {
+        Ok(Status::Breakpoint)
+    } else {
+        vm.run_until(|vm| returning(vm).is_some())
+    };
+    match result {
+        Ok(Status::Breakpoint) => match returning(vm) {
+            Some(value) => writeln!(vm.output, "{}", value),
+            None => writeln!(vm.output, "stopped at a breakpoint in {}", vm.location()),
+        },
+        Ok(Status::Halted) => Ok(()),
+        Ok(Status::OutOfFuel) => writeln!(vm.output, "error in {}: out of fuel", vm.location()),
+        Ok(Status::TimedOut) => writeln!(vm.output, "error in {}: timed out", vm.location()),
+        Ok(Status::BlockedOnInput) => {
+            writeln!(vm.output, "error in {}: no input", vm.location())
+        }
+        Ok(Status::Running) | Ok(Status::Yielded) => Ok(()),
+        Err(e) => {
+            writeln!(vm.output, "error in {}: {}", vm.location(), e)?;
+            if !vm.stack.is_empty() {
+                for line in vm.backtrace() {
+                    writeln!(vm.output, "  {}", line)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// The value the module is about to return, when the next instruction is a
+// `return` at the top level of the main program.
+fn returning(vm: &VMState) -> Option<Val> {
+    let instruction = vm.func.instructions.get(vm.pc)?;
+    let top_level = vm.stack.is_empty() && vm.coroutine.is_none() && vm.thread == 0;
+    if !top_level || instruction.opcode != Opcodes::Return {
+        return None;
+    }
+    vm.registers.get(vm.reg_window + instruction.r_x).cloned()
+}
+
+// The lines of a module read so far, and the number of instructions still
+// to come in the module and each function being loaded, innermost last.
+// Lines are counted the way the loader counts instructions: debug
+// directives don't count, and a nested `.load` counts as one instruction of
+// the function it is in.
+#[derive(Default)]
+struct Chunk {
+    text: String,
+    remaining: Vec<usize>,
+}
+
+impl Chunk {
+    fn started(&self) -> bool {
+        !self.text.is_empty()
+    }
+
+    // Adds a line and returns whether the module is complete.
+    fn add(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            [".load", "module", count] if !self.started() => {
+                self.remaining.push(count_of(count, line)?);
+            }
+            _ if !self.started() => return Err(format!("expected .load module, got {}", line)),
+            [".source", ..] | [".line", ..] | [".name", ..] => {}
+            [".load", _, "function", _, count, ..] => {
+                self.count_instruction();
+                self.remaining.push(count_of(count, line)?);
+            }
+            [".load", ..] => return Err(format!("can't parse {}", line)),
+            _ => self.count_instruction(),
+        }
+        if self.started() || !words.is_empty() {
+            self.text.push_str(line);
+            self.text.push('\n');
+        }
+        while self.remaining.last() == Some(&0) {
+            self.remaining.pop();
+        }
+        Ok(self.started() && self.remaining.is_empty())
+    }
+
+    fn count_instruction(&mut self) {
+        if let Some(remaining) = self.remaining.last_mut() {
+            *remaining -= 1;
+        }
+    }
+}
+
+fn count_of(word: &str, line: &str) -> Result<usize, String> {
+    word.parse()
+        .map_err(|_| format!("expected a number of instructions in {}", line))
+}
@@ -0,0 +1,253 @@
+use crate::{
+    opcodes::{Instruction, Opcodes},
+    value::value::{VMFunction, Val},
+};
+
+#[derive(Debug, Default)]
+pub struct Tracer {
+    pub function: Option<String>,
+    pub limit: Option<usize>,
+    // The line of the instruction being executed, until the register it
+    // writes is in place.
+    pub pending: Option<TraceLine>,
+    lines: usize,
+}
+
+#[derive(Debug)]
+pub struct TraceLine {
+    text: String,
+    write: Option<usize>,
+}
+
+impl Tracer {
+    pub fn new(function: Option<String>, limit: Option<usize>) -> Self {
+        Tracer {
+            function,
+            limit,
+            pending: None,
+            lines: 0,
+        }
+    }
+
+    fn wants(&self, function: &str) -> bool {
+        let under_limit = self.limit.is_none_or(|limit| self.lines < limit);
+        let matches = self.function.as_ref().is_none_or(|f| f == function);
+        under_limit && matches
+    }
+
+    // Formats everything known before the instruction runs. Instructions that
+    // write a register are finished by `finish` once the value is in place.
+    pub fn begin(
+        &self,
+        function: &VMFunction,
+        pc: usize,
+        depth: usize,
+        instruction: &Instruction,
+        window: &[Val],
+    ) -> Option<TraceLine> {
+        if !self.wants(&function.name) {
+            return None;
+        }
+        let (reads, write) = operands(instruction);
+        let reads: Vec<String> = reads
+            .into_iter()
+            .map(|r| match window.get(r) {
+                Some(v) => format!("r{}={}", r, v),
+                None => format!("r{}=?", r),
+            })
+            .collect();
+        let text = format!(
+            "[{}] {} {:>4}: {:<29} {}",
+            depth,
+            function.name,
+            pc,
+            instruction.to_string(),
+            reads.join(" ")
+        )
+        .trim_end()
+        .to_string();
+        let text = match function.location(pc) {
+            Some(location) => format!("{} ; {}", text, location),
+            None => text,
+        };
+        Some(TraceLine { text, write })
+    }
+
+    pub fn finish(&mut self, line: TraceLine, window: &[Val]) {
+        self.lines += 1;
+        match line.write {
+            Some(r) => match window.get(r) {
+                Some(v) => eprintln!("{} => r{}={}", line.text, r, v),
+                None => eprintln!("{} => r{}=?", line.text, r),
+            },
+            None => eprintln!("{}", line.text),
+        }
+        if self.limit == Some(self.lines) {
+            eprintln!("trace limit of {} lines reached", self.lines);
+        }
+    }
+
+    // Prints the pending line of an instruction that failed, which never
+    // wrote its register.
+    pub fn fail(&mut self) {
+        if let Some(mut line) = self.pending.take() {
+            line.write = None;
+            self.finish(line, &[]);
+        }
+    }
+}
+
+impl TraceLine {
+    pub fn writes(&self) -> bool {
+        self.write.is_some()
+    }
+}
+
+// Registers (relative to the current window) read by an instruction, and the
+// register it writes in the same window, if any.
+fn operands(instruction: &Instruction) -> (Vec<usize>, Option<usize>) {
+    let (x, y, z) = (instruction.r_x, instruction.r_y, instruction.r_z);
+    match instruction.opcode {
+        Opcodes::Add
+        | Opcodes::Subtract
+        | Opcodes::Multiply
+        | Opcodes::Divide
+        | Opcodes::IDiv
+        | Opcodes::Equal
+        | Opcodes::Greater
+        | Opcodes::Less
+        | Opcodes::LessEq
+        | Opcodes::Cons => (vec![y, z], Some(x)),
+        Opcodes::Mov
+        | Opcodes::IsNumber
+        | Opcodes::IsSymbol
+        | Opcodes::IsBoolean
+        | Opcodes::IsNull
+        | Opcodes::IsNil
+        | Opcodes::Car
+        | Opcodes::Cdr
+        | Opcodes::Pair
+        | Opcodes::NotEqual
+        | Opcodes::MakeClosure
+        | Opcodes::MakeCoroutine
+        | Opcodes::IsDone
+        | Opcodes::Spawn
+        | Opcodes::Receive
+        | Opcodes::IsEof
+        | Opcodes::OpenInput
+        | Opcodes::OpenOutput
+        | Opcodes::PortReadLine
+        | Opcodes::PortRead
+        | Opcodes::GetClSlot => (vec![y], Some(x)),
+        Opcodes::Not => (vec![y], Some(y)),
+        Opcodes::SetClSlot => (vec![x, y], Some(x)),
+        Opcodes::SetCar | Opcodes::SetCdr => (vec![x, y], None),
+        Opcodes::LoadLiteral
+        | Opcodes::GetGlobal
+        | Opcodes::MakeChannel
+        | Opcodes::ReadLine
+        | Opcodes::ReadNumber
+        | Opcodes::Read => (vec![], Some(x)),
+        Opcodes::Send | Opcodes::PortWrite | Opcodes::PortWriteLine => (vec![x, y], None),
+        Opcodes::Print
+        | Opcodes::If
+        | Opcodes::Return
+        | Opcodes::Check
+        | Opcodes::Expect
+        | Opcodes::Assert
+        | Opcodes::SetGlobal
+        | Opcodes::ClosePort
+        | Opcodes::Error => (vec![x], None),
+        Opcodes::Call => ((y..=z).collect(), None),
+        Opcodes::TailCall => ((x..=y).collect(), None),
+        Opcodes::Apply => (vec![y, z], None),
+        Opcodes::CallCC | Opcodes::Yield => (vec![y], None),
+        Opcodes::Resume => (vec![y, z], None),
+        Opcodes::Halt | Opcodes::Goto => (vec![], None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmrun::{run, Status};
+    use crate::{error::VMError, loader::load_str, vmstate::init_vm_state};
+
+    // Calls f twice; f runs two instructions each time and the module six.
+    const TWO_CALLS: &str = ".load module 6
+.load 0 function 1 2
++ 2 1 1
+return 2
+loadliteral 1 3
+call 2 0 1
+mov 1 2
+call 3 0 1
+halt
+";
+
+    // Runs a module and returns how it ended and the number of lines traced.
+    fn traced_run(source: &str, tracer: Tracer) -> (Result<Status, VMError>, usize) {
+        let mut vm = init_vm_state();
+        vm.tracer = Some(tracer);
+        let module = load_str(source, &mut vm).unwrap();
+        let result = run(&mut vm, module);
+        (result, vm.tracer.unwrap().lines)
+    }
+
+    fn traced(source: &str, tracer: Tracer) -> usize {
+        traced_run(source, tracer).1
+    }
+
+    #[test]
+    fn traces_every_instruction() {
+        let (result, lines) = traced_run(TWO_CALLS, Tracer::new(None, None));
+        assert_eq!(result, Ok(Status::Halted));
+        assert_eq!(lines, 10);
+    }
+
+    #[test]
+    fn traces_only_the_named_function() {
+        let f = Some("fn@0".to_string());
+        assert_eq!(traced(TWO_CALLS, Tracer::new(f, None)), 4);
+        let g = Some("g".to_string());
+        assert_eq!(traced(TWO_CALLS, Tracer::new(g, None)), 0);
+    }
+
+    #[test]
+    fn stops_tracing_at_the_limit() {
+        assert_eq!(traced(TWO_CALLS, Tracer::new(None, Some(3))), 3);
+        let f = Some("fn@0".to_string());
+        assert_eq!(traced(TWO_CALLS, Tracer::new(f, Some(3))), 3);
+    }
+
+    #[test]
+    fn traces_the_instruction_that_fails() {
+        let (result, lines) =
+            traced_run(".load module 2\n+ 2 1 1\nhalt\n", Tracer::new(None, None));
+        assert!(result.is_err());
+        assert_eq!(lines, 1);
+    }
+
+    #[test]
+    fn formats_reads_and_writes() {
+        let mut vm = init_vm_state();
+        let module = load_str(".load module 2\n+ 2 1 1\nhalt\n", &mut vm).unwrap();
+        let add = module.instructions[0];
+        let line = Tracer::new(None, None)
+            .begin(&module, 0, 0, &add, &[Val::Nil, Val::Num(3)])
+            .unwrap();
+        assert!(line.writes());
+        assert!(line.text.ends_with("r1=3 r1=3"), "{}", line.text);
+    }
+
+    #[test]
+    fn shows_registers_past_the_window_as_unknown() {
+        let mut vm = init_vm_state();
+        let module = load_str(".load module 2\nmov 1 9\nhalt\n", &mut vm).unwrap();
+        let mov = module.instructions[0];
+        let line = Tracer::new(None, None)
+            .begin(&module, 0, 0, &mov, &[Val::Nil])
+            .unwrap();
+        assert!(line.text.ends_with("r9=?"), "{}", line.text);
+    }
+}
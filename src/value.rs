@@ -1,8 +1,15 @@
+#[allow(clippy::module_inception)]
 pub mod value {
     use std::fmt::{self, Display};
+    use std::hash::{Hash, Hasher};
+    use std::rc::Rc;
 
+    use crate::error::VMError;
     use crate::opcodes::Instruction;
+    use crate::vmstack::vmstack::Continuation;
+    use crate::vmstate::VMState;
 
+    #[allow(clippy::derived_hash_with_manual_eq)]
     #[derive(Debug, Clone, Hash)]
     pub enum Val {
         Nil,
@@ -13,31 +20,50 @@ pub mod value {
         String(String),
         VMFunction(VMFunction),
         Closure(VMFunction, Vec<Val>),
+        Continuation(Rc<Continuation>),
+        // An index into the VM's coroutines.
+        Coroutine(usize),
+        // An index into the VM's channels.
+        Channel(usize),
+        // An index into the VM's ports.
+        Port(usize),
+        Native(Rc<Native>),
+        // What the input opcodes return at the end of the input.
+        Eof,
     }
 
     impl Val {
-        pub fn as_num(&self) -> i32 {
+        pub fn as_num(&self) -> Result<i32, VMError> {
             match self {
-                Val::Num(i) => *i,
-                _ => panic!("Can't be interpreted as a number"),
+                Val::Num(i) => Ok(*i),
+                _ => Err(VMError::runtime(format!(
+                    "{} can't be interpreted as a number",
+                    self
+                ))),
             }
         }
         pub fn as_bool(&self) -> bool {
             match self {
                 Val::Nil => false,
                 Val::EmptyList => false,
-                Val::Num(i) => (i > &0),
-                Val::Bool(b) => b.clone(),
+                Val::Num(i) => i > &0,
+                Val::Bool(b) => *b,
                 Val::String(_) => true,
                 Val::VMFunction(_) => true,
                 Val::Cons(_, _) => true,
                 Val::Closure(_, _) => true,
+                Val::Continuation(_) => true,
+                Val::Coroutine(_) => true,
+                Val::Channel(_) => true,
+                Val::Port(_) => true,
+                Val::Native(_) => true,
+                Val::Eof => true,
             }
         }
-        pub fn as_string(&self) -> String {
+        pub fn as_string(&self) -> Result<String, VMError> {
             match self {
-                Val::String(s) => s.clone(),
-                _ => panic!("Can't make a string from non-string VM Value"),
+                Val::String(s) => Ok(s.clone()),
+                _ => Err(VMError::runtime(format!("{} isn't a string", self))),
             }
         }
         pub fn to_num(n: i32) -> Self {
@@ -47,29 +73,52 @@ pub mod value {
     impl Display for Val {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             match self {
-                Val::Nil => write!(f, "{}", "nil"),
+                Val::Nil => write!(f, "nil"),
                 Val::Num(i) => write!(f, "{}", i),
                 Val::Bool(b) => write!(f, "{}", b),
                 Val::String(s) => write!(f, "{}", s),
-                Val::VMFunction(_) => write!(f, "A VM Function"),
+                Val::VMFunction(fun) => write!(f, "#<function {}/{}>", fun.name, fun.arity_label()),
                 Val::EmptyList => write!(f, "'()"),
                 Val::Cons(x, xs) => write!(f, "{} {}", x, xs),
-                Val::Closure(_f, _cl) => write!(f, "A Vm Closure {:?}", _cl),
+                Val::Closure(fun, _) => write!(f, "#<closure {}/{}>", fun.name, fun.arity_label()),
+                Val::Continuation(_) => write!(f, "#<continuation>"),
+                Val::Coroutine(i) => write!(f, "#<coroutine {}>", i),
+                Val::Channel(i) => write!(f, "#<channel {}>", i),
+                Val::Port(i) => write!(f, "#<port {}>", i),
+                Val::Native(native) => write!(f, "#<primitive {}/{}>", native.name, native.arity),
+                Val::Eof => write!(f, "#<eof>"),
             }
         }
     }
 
+    pub type NativeFn = dyn Fn(&mut VMState, &[Val]) -> Result<Val, VMError>;
+
+    // A function implemented in Rust, called with exactly `arity` arguments
+    // and the VM, through which it can call back into VM code.
+    pub struct Native {
+        pub name: String,
+        pub arity: usize,
+        pub function: Box<NativeFn>,
+    }
+
+    impl fmt::Debug for Native {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Native({}/{})", self.name, self.arity)
+        }
+    }
+
+    impl Hash for Native {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.name.hash(state);
+            self.arity.hash(state);
+        }
+    }
+
     impl PartialEq for Val {
         fn eq(&self, other: &Self) -> bool {
             match self {
-                Val::Nil => match other {
-                    Val::Nil => true,
-                    _ => false,
-                },
-                Val::EmptyList => match other {
-                    Val::EmptyList => true,
-                    _ => false,
-                },
+                Val::Nil => matches!(other, Val::Nil),
+                Val::EmptyList => matches!(other, Val::EmptyList),
                 Val::Num(i) => match other {
                     Val::Num(j) => i == j,
                     _ => false,
@@ -88,15 +137,72 @@ pub mod value {
                     _ => false,
                 },
                 Val::Closure(_, _) => false,
+                Val::Continuation(_) => false,
+                Val::Coroutine(i) => matches!(other, Val::Coroutine(j) if i == j),
+                Val::Channel(i) => matches!(other, Val::Channel(j) if i == j),
+                Val::Port(i) => matches!(other, Val::Port(j) if i == j),
+                Val::Native(f) => matches!(other, Val::Native(g) if Rc::ptr_eq(f, g)),
+                Val::Eof => matches!(other, Val::Eof),
             }
         }
     }
     impl Eq for Val {}
     #[derive(Debug, Clone, Hash)]
     pub struct VMFunction {
+        pub name: String,
+        // Tells the function apart from every other one loaded into the VM,
+        // whatever their names.
+        pub id: usize,
         pub arity: i32,
+        // Takes the arguments after the first `arity` as a list.
+        pub variadic: bool,
         pub nregs: i32,
         pub size: i32,
         pub instructions: Vec<Instruction>,
+        pub source: Option<String>,
+        pub positions: Vec<Option<Position>>,
+    }
+
+    // Where in the source file an instruction came from, given by `.line`
+    // directives in the loaded module.
+    #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+    pub struct Position {
+        pub line: u32,
+        pub column: u32,
+    }
+
+    impl VMFunction {
+        pub fn position(&self, pc: usize) -> Option<Position> {
+            self.positions.get(pc).copied().flatten()
+        }
+        pub fn location(&self, pc: usize) -> Option<String> {
+            let position = self.position(pc)?;
+            Some(format!(
+                "{}:{}:{}",
+                self.source.as_deref().unwrap_or("?"),
+                position.line,
+                position.column
+            ))
+        }
+        pub fn arity_label(&self) -> String {
+            if self.variadic {
+                format!("{}+", self.arity)
+            } else {
+                self.arity.to_string()
+            }
+        }
+        // The number of registers the instructions refer to, which is the
+        // size of the window the function uses.
+        pub fn registers_used(&self) -> usize {
+            self.instructions
+                .iter()
+                .map(|i| i.r_x.max(i.r_y).max(i.r_z) + 1)
+                .max()
+                .unwrap_or(0)
+        }
+        // The first source line with code in this function.
+        pub fn first_line(&self) -> Option<u32> {
+            self.positions.iter().flatten().map(|p| p.line).next()
+        }
     }
 }
@@ -1,284 +1,1716 @@
 use crate::{
-    value::{self, value::VMFunction},
-    vmstack::vmstack::Activation,
-    vmstate::VMState,
+    error::VMError,
+    input::{self, Reading},
+    memory::{allocate, heap_size, slots},
+    ports,
+    value::{
+        self,
+        value::{Native, VMFunction},
+    },
+    vmstack::vmstack::{
+        Activation, Context, Continuation, Coroutine, CoroutineState, Thread, ThreadState,
+    },
+    vmstate::{main_thread, VMState, TASK_REGISTERS},
 };
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::Instant;
 use value::value::Val;
 
-pub fn run(vm: &mut VMState, function: &mut VMFunction) -> () {
-    let mut i = 0;
-    let mut reg_window = 0;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Halted,
+    OutOfFuel,
+    TimedOut,
+    // Returned by `run_until` when it has run the instructions it was given,
+    // and when it stopped at a breakpoint or where its predicate held.
+    Yielded,
+    Breakpoint,
+    // The program is waiting for input its input source doesn't have yet.
+    // Running it again once there is some carries on where it left off.
+    BlockedOnInput,
+}
+
+pub fn start(vm: &mut VMState, function: VMFunction) {
+    vm.func = function;
+    vm.pc = 0;
+    vm.reg_window = 0;
+    vm.stack.clear();
+    vm.coroutine = None;
+    vm.threads = vec![main_thread(&vm.func)];
+    vm.thread = 0;
+    vm.slice = 0;
+    if let Some(coverage) = vm.coverage.as_mut() {
+        coverage.enter(&vm.func);
+    }
+}
+
+pub fn run(vm: &mut VMState, function: VMFunction) -> Result<Status, VMError> {
+    start(vm, function);
+    resume(vm)
+}
+
+// How many instructions run between checks of the deadline.
+const DEADLINE_INTERVAL: u64 = 4096;
+
+// Runs until the program halts, uses up `vm.fuel` or passes `vm.deadline`.
+// After running out of fuel, it can be refilled and the program resumed.
+pub fn resume(vm: &mut VMState) -> Result<Status, VMError> {
+    run_until(vm, None, |_| false)
+}
+
+// Like `resume`, but also gives control back after `limit` instructions, if
+// given, or before an instruction in `vm.breakpoints` or one for which `stop`
+// holds. Every stop leaves the program where calling this again continues
+// it, so an event loop can run it a slice at a time.
+pub fn run_until(
+    vm: &mut VMState,
+    limit: Option<u64>,
+    mut stop: impl FnMut(&VMState) -> bool,
+) -> Result<Status, VMError> {
+    let mut count: u64 = 0;
     loop {
-        if i >= function.instructions.len() {
-            return;
-        }
-        let instruction = function.instructions[i];
-        i += 1;
-        let x = vm
-            .registers
-            .get(reg_window + instruction.r_x)
-            .expect("Expected something in register x")
-            .clone();
-        let y = vm
-            .registers
-            .get(reg_window + instruction.r_y)
-            .expect("Expected something in register y")
-            .clone();
-        let z = vm
-            .registers
-            .get(reg_window + instruction.r_z)
-            .expect("Expected something in register z")
-            .clone();
+        // The deadline is also checked on entry and at the limit, so running
+        // a slice smaller than the interval at a time still times out.
+        let at_limit = limit.is_some_and(|limit| count >= limit);
+        if (at_limit || count.is_multiple_of(DEADLINE_INTERVAL))
+            && vm.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return Ok(Status::TimedOut);
+        }
+        if at_limit {
+            return Ok(Status::Yielded);
+        }
+        match vm.fuel {
+            Some(0) => return Ok(Status::OutOfFuel),
+            Some(fuel) => vm.fuel = Some(fuel - 1),
+            None => {}
+        }
+        count += 1;
+        match step(vm) {
+            Ok(Status::Running) => {}
+            Ok(status) => return Ok(status),
+            // A primitive's call back into the VM stopped; the instruction
+            // that made it runs again when the program is resumed.
+            Err(VMError::OutOfFuel) => return Ok(Status::OutOfFuel),
+            Err(VMError::TimedOut) => return Ok(Status::TimedOut),
+            Err(e) => return Err(e),
+        }
+        if at_breakpoint(vm) || stop(vm) {
+            return Ok(Status::Breakpoint);
+        }
+    }
+}
 
-        match instruction.opcode {
-            crate::opcodes::Opcodes::Add => {
-                let num = Val::to_num(Val::as_num(&y) + Val::as_num(&z));
-                vm.registers[reg_window + instruction.r_x] = num;
+// Whether the next instruction is one in `vm.breakpoints`.
+pub fn at_breakpoint(vm: &VMState) -> bool {
+    vm.breakpoints
+        .iter()
+        .any(|(name, pc)| *pc == vm.pc && *name == vm.func.name)
+}
+
+// Calls `function` with `args` from Rust and runs it to completion. The call
+// gets a window above every register the current function uses, so it can
+// happen in the middle of a run, and the interpreter is left as it was.
+// Threads are not switched while it runs.
+pub fn invoke(vm: &mut VMState, function: &Val, args: &[Val]) -> Result<Val, VMError> {
+    let (func, pc, window, depth) = (vm.func.clone(), vm.pc, vm.reg_window, vm.stack.len());
+    let outer = vm.rust_call_depth.replace(depth);
+    let result = run_call(vm, function, args, depth);
+    vm.rust_call_depth = outer;
+    vm.stack.truncate(depth);
+    vm.func = func;
+    vm.pc = pc;
+    vm.reg_window = window;
+    result
+}
+
+fn run_call(vm: &mut VMState, function: &Val, args: &[Val], depth: usize) -> Result<Val, VMError> {
+    let base = vm.reg_window + vm.func.registers_used();
+    store(vm, base, function.clone())?;
+    for (i, arg) in args.iter().enumerate() {
+        store(vm, base + i + 1, arg.clone())?;
+    }
+    vm.reg_window = base;
+    call(vm, function, 0, 0, args.len())?;
+    if let Some(profiler) = vm.profiler.as_mut() {
+        if vm.stack.len() > depth {
+            profiler.after(crate::opcodes::Opcodes::Call, &vm.func.name);
+        }
+    }
+    if let Some(coverage) = vm.coverage.as_mut() {
+        if vm.stack.len() > depth {
+            coverage.enter(&vm.func);
+        }
+    }
+    let mut count: u64 = 0;
+    while vm.stack.len() > depth {
+        match vm.fuel {
+            Some(0) => return Err(VMError::OutOfFuel),
+            Some(fuel) => vm.fuel = Some(fuel - 1),
+            None => {}
+        }
+        if count.is_multiple_of(DEADLINE_INTERVAL)
+            && vm.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return Err(VMError::TimedOut);
+        }
+        count += 1;
+        let pc = vm.pc;
+        let status = execute(vm).inspect_err(|_| vm.pc = pc)?;
+        if status == Status::Halted {
+            return Err(VMError::runtime("Halted inside a call from Rust"));
+        }
+        if status == Status::BlockedOnInput {
+            return Err(VMError::runtime("Blocked on input inside a call from Rust"));
+        }
+        if let ThreadState::Blocked(_) = vm.threads[vm.thread].state {
+            vm.threads[vm.thread].state = ThreadState::Runnable;
+            return Err(VMError::runtime("Blocked inside a call from Rust"));
+        }
+    }
+    Ok(vm.registers[base].clone())
+}
+
+// Executes the instruction at `vm.pc` in `vm.func`, leaving the interpreter
+// ready to execute the next one. When the instruction fails, `vm.pc` is left
+// pointing at it.
+pub fn step(vm: &mut VMState) -> Result<Status, VMError> {
+    let pc = vm.pc;
+    let result = execute(vm).and_then(|status| schedule(vm, status));
+    if result.is_err() {
+        vm.pc = pc;
+    }
+    result
+}
+
+// Executes one instruction. When it fails, its trace line, if it was still
+// waiting for the register the instruction writes, is printed without it.
+fn execute(vm: &mut VMState) -> Result<Status, VMError> {
+    dispatch(vm).inspect_err(|_| {
+        if let Some(tracer) = vm.tracer.as_mut() {
+            tracer.fail();
+        }
+    })
+}
+
+fn dispatch(vm: &mut VMState) -> Result<Status, VMError> {
+    if vm.pc >= vm.func.instructions.len() {
+        return Ok(Status::Halted);
+    }
+    let instruction = vm.func.instructions[vm.pc];
+    let window = vm.reg_window;
+    let depth = vm.stack.len();
+    let trace = match &vm.tracer {
+        Some(tracer) => tracer.begin(
+            &vm.func,
+            vm.pc,
+            vm.stack.len(),
+            &instruction,
+            &vm.registers[window..],
+        ),
+        None => None,
+    };
+    let trace = match trace {
+        Some(line) if !line.writes() => {
+            vm.tracer
+                .as_mut()
+                .unwrap()
+                .finish(line, &vm.registers[window..]);
+            None
+        }
+        line => line,
+    };
+    if let Some(tracer) = vm.tracer.as_mut() {
+        tracer.pending = trace;
+    }
+    if let Some(profiler) = vm.profiler.as_mut() {
+        profiler.instruction(instruction.opcode, &vm.func.name);
+    }
+    if let Some(coverage) = vm.coverage.as_mut() {
+        coverage.hit(&vm.func, vm.pc);
+    }
+    vm.pc += 1;
+    let x = register(vm, window + instruction.r_x)?;
+    let y = register(vm, window + instruction.r_y)?;
+    let z = register(vm, window + instruction.r_z)?;
+    // Invoking a continuation moves to another stack altogether, as switching
+    // coroutines does, which the profiler has to catch up with.
+    let throws = match instruction.opcode {
+        crate::opcodes::Opcodes::Call
+        | crate::opcodes::Opcodes::Apply
+        | crate::opcodes::Opcodes::CallCC => matches!(y, Val::Continuation(_)),
+        crate::opcodes::Opcodes::TailCall => matches!(x, Val::Continuation(_)),
+        _ => false,
+    };
+    let coroutine = vm.coroutine;
+
+    match instruction.opcode {
+        crate::opcodes::Opcodes::Add => {
+            let num = Val::to_num(y.as_num()? + z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::LoadLiteral => {
+            if let Some(v) = vm.literals.get(instruction.slot) {
+                store(vm, window + instruction.r_x, v.clone())?;
             }
-            crate::opcodes::Opcodes::LoadLiteral => {
-                if let Some(v) = vm.literals.get(instruction.slot) {
-                    vm.registers[reg_window + instruction.r_x] = v.clone();
-                }
+        }
+        crate::opcodes::Opcodes::Print => {
+            if let Some(x) = vm.registers.get(vm.reg_window + instruction.r_x) {
+                writeln!(vm.output, "{}", x)?;
             }
-            crate::opcodes::Opcodes::Print => {
-                if let Some(x) = vm.registers.get(reg_window + instruction.r_x) {
-                    println!("{}", x);
-                }
+        }
+        crate::opcodes::Opcodes::Halt => return Ok(Status::Halted),
+        crate::opcodes::Opcodes::Goto => match instruction.goto.is_positive() {
+            true => vm.pc += instruction.goto as usize - 1,
+            false => vm.pc -= instruction.goto.unsigned_abs() as usize + 1,
+        },
+        crate::opcodes::Opcodes::Not => {
+            vm.registers[vm.reg_window + instruction.r_y] = Val::Bool(!Val::as_bool(&y))
+        }
+        crate::opcodes::Opcodes::Mov => store(vm, window + instruction.r_x, y)?,
+        crate::opcodes::Opcodes::If => {
+            if !Val::as_bool(&vm.registers[vm.reg_window + instruction.r_x]) {
+                vm.pc += 1;
             }
-            crate::opcodes::Opcodes::Halt => return,
-            crate::opcodes::Opcodes::Goto => match instruction.goto.is_positive() {
-                true => i += instruction.goto as usize - 1,
-                false => i -= instruction.goto.abs() as usize + 1,
-            },
-            crate::opcodes::Opcodes::Not => {
-                vm.registers[reg_window + instruction.r_y] = Val::Bool(!Val::as_bool(&y))
+        }
+        crate::opcodes::Opcodes::Subtract => {
+            let num = Val::to_num(y.as_num()? - z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Multiply => {
+            let num = Val::to_num(y.as_num()? * z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Divide => {
+            let num = Val::to_num(divide(y.as_num()?, z.as_num()?)?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Equal => {
+            let num = Val::Bool(y == z);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Check => {
+            if let Some(v) = vm.literals.get(instruction.slot) {
+                vm.test_suite.check(
+                    v.as_string()?,
+                    vm.registers[vm.reg_window + instruction.r_x].clone(),
+                )
             }
-            crate::opcodes::Opcodes::Mov => {
-                vm.registers[reg_window + instruction.r_x] = y.clone();
+        }
+        crate::opcodes::Opcodes::Expect => {
+            if let Some(v) = vm.literals.get(instruction.slot) {
+                vm.test_suite.expect(
+                    v.as_string()?,
+                    vm.registers[vm.reg_window + instruction.r_x].clone(),
+                    &mut *vm.output,
+                )?
             }
-            crate::opcodes::Opcodes::If => {
-                if !Val::as_bool(&vm.registers[reg_window + instruction.r_x]) {
-                    i += 1;
-                }
+        }
+        crate::opcodes::Opcodes::SetGlobal => {
+            allocate(vm, heap_size(&x))?;
+            vm.globals.insert(
+                vm.literals[instruction.slot].clone(),
+                vm.registers[vm.reg_window + instruction.r_x].clone(),
+            );
+        }
+        crate::opcodes::Opcodes::GetGlobal => {
+            let v = vm
+                .globals
+                .get(&vm.literals[instruction.slot])
+                .ok_or_else(|| {
+                    VMError::runtime(format!(
+                        "Undefined global {}",
+                        vm.literals[instruction.slot]
+                    ))
+                })?
+                .clone();
+            store(vm, window + instruction.r_x, v)?;
+        }
+        crate::opcodes::Opcodes::IsSymbol => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::String(_) => Val::Bool(true),
+                _ => Val::Bool(false),
             }
-            crate::opcodes::Opcodes::Subtract => {
-                let num = Val::to_num(Val::as_num(&y) - Val::as_num(&z));
-                vm.registers[reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::IsBoolean => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::Bool(_) => Val::Bool(true),
+                _ => Val::Bool(false),
             }
-            crate::opcodes::Opcodes::Multiply => {
-                let num = Val::to_num(Val::as_num(&y) * Val::as_num(&z));
-                vm.registers[reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::IsNil => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::Nil => Val::Bool(true),
+                _ => Val::Bool(false),
             }
-            crate::opcodes::Opcodes::Divide => {
-                let num = Val::to_num(Val::as_num(&y) / Val::as_num(&z));
-                vm.registers[reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::IsNull => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::EmptyList => Val::Bool(true),
+                _ => Val::Bool(false),
             }
-            crate::opcodes::Opcodes::Equal => {
-                let num = Val::Bool(y == z);
-                vm.registers[reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::IsNumber => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::Num(_) => Val::Bool(true),
+                _ => Val::Bool(false),
             }
-            crate::opcodes::Opcodes::Check => {
-                if let Some(v) = vm.literals.get(instruction.slot) {
-                    vm.test_suite.check(
-                        v.as_string(),
-                        vm.registers[reg_window + instruction.r_x].clone(),
-                    )
-                }
+        }
+        crate::opcodes::Opcodes::Greater => {
+            let num = Val::Bool(y.as_num()? > z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Less => {
+            let num = Val::Bool(y.as_num()? < z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::LessEq => {
+            let num = Val::Bool(y.as_num()? <= z.as_num()?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Return => {
+            if return_value(vm, x)? == Status::Halted {
+                return Ok(Status::Halted);
             }
-            crate::opcodes::Opcodes::Expect => {
-                if let Some(v) = vm.literals.get(instruction.slot) {
-                    vm.test_suite.expect(
-                        v.as_string(),
-                        vm.registers[reg_window + instruction.r_x].clone(),
-                    )
-                }
+        }
+        crate::opcodes::Opcodes::Call => {
+            let nargs = argument_count(instruction.r_y, instruction.r_z)?;
+            call(vm, &y, instruction.r_x, instruction.r_y, nargs)?
+        }
+        crate::opcodes::Opcodes::Apply => {
+            allocate(vm, heap_size(&z))?;
+            let mut args = z.clone();
+            let mut nargs = 0;
+            while let Val::Cons(arg, rest) = args {
+                nargs += 1;
+                set_register(vm, window + instruction.r_y + nargs, *arg)?;
+                args = *rest;
             }
-            crate::opcodes::Opcodes::SetGlobal => {
-                vm.globals.insert(
-                    vm.literals[instruction.slot].clone(),
-                    vm.registers[reg_window + instruction.r_x].clone(),
-                );
-                ()
-            }
-            crate::opcodes::Opcodes::GetGlobal => {
-                vm.registers[reg_window + instruction.r_x] = vm
-                    .globals
-                    .get(&vm.literals[instruction.slot])
-                    .expect(&format!("Expected {}", vm.literals[instruction.slot]))
-                    .clone();
-            }
-            crate::opcodes::Opcodes::IsSymbol => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::String(_) => Val::Bool(true),
-                    _ => Val::Bool(false),
-                }
+            if args != Val::EmptyList {
+                return Err(VMError::runtime(format!(
+                    "Can't apply to {}, which isn't a list",
+                    z
+                )));
             }
-            crate::opcodes::Opcodes::IsBoolean => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::Bool(_) => Val::Bool(true),
-                    _ => Val::Bool(false),
-                }
+            call(vm, &y, instruction.r_x, instruction.r_y, nargs)?
+        }
+        crate::opcodes::Opcodes::CallCC => {
+            let used = (window + vm.func.registers_used()).min(vm.registers.len());
+            let captured: usize = vm.registers[..used].iter().map(heap_size).sum();
+            allocate(vm, slots(used) + captured)?;
+            let k = Continuation {
+                stack: vm.stack.clone(),
+                fun: vm.func.clone(),
+                program_counter: vm.pc,
+                register_window: window,
+                registers: vm.registers[..used].to_vec(),
+                dest: window + instruction.r_x,
+                thread: vm.thread,
+                coroutine: vm.coroutine,
+            };
+            set_register(
+                vm,
+                window + instruction.r_y + 1,
+                Val::Continuation(Rc::new(k)),
+            )?;
+            call(vm, &y, instruction.r_x, instruction.r_y, 1)?
+        }
+        crate::opcodes::Opcodes::MakeCoroutine => match &y {
+            Val::VMFunction(f) | Val::Closure(f, _) => {
+                check_arity(f, 1)?;
+                allocate(vm, slots(TASK_REGISTERS) + heap_size(&y))?;
+                let mut registers = vec![Val::Nil; TASK_REGISTERS];
+                registers[0] = y.clone();
+                vm.coroutines.push(Coroutine {
+                    context: Context::new(f.clone(), registers),
+                    state: CoroutineState::Fresh,
+                    dest: 1,
+                    resumer_dest: 0,
+                    resumer: None,
+                });
+                vm.registers[window + instruction.r_x] = Val::Coroutine(vm.coroutines.len() - 1);
             }
-            crate::opcodes::Opcodes::IsNil => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::Nil => Val::Bool(true),
-                    _ => Val::Bool(false),
-                }
+            _ => {
+                return Err(VMError::runtime(format!(
+                    "Can't make a coroutine from {}, which isn't a function",
+                    y
+                )))
             }
-            crate::opcodes::Opcodes::IsNull => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::EmptyList => Val::Bool(true),
-                    _ => Val::Bool(false),
+        },
+        crate::opcodes::Opcodes::Resume => match y {
+            Val::Coroutine(id) => {
+                let co = &mut vm.coroutines[id];
+                match co.state {
+                    CoroutineState::Running => {
+                        return Err(VMError::runtime("Can't resume a running coroutine"))
+                    }
+                    CoroutineState::Done => {
+                        return Err(VMError::runtime("Can't resume a finished coroutine"))
+                    }
+                    CoroutineState::Fresh | CoroutineState::Suspended => {}
                 }
+                allocate(vm, heap_size(&z))?;
+                let co = &mut vm.coroutines[id];
+                co.context.registers[co.dest] = z;
+                co.state = CoroutineState::Running;
+                co.resumer_dest = window + instruction.r_x;
+                co.resumer = vm.coroutine;
+                vm.coroutine = Some(id);
+                switch(vm, Saved::Coroutine(id));
+            }
+            _ => {
+                return Err(VMError::runtime(format!(
+                    "Can't resume {}, which isn't a coroutine",
+                    y
+                )))
+            }
+        },
+        crate::opcodes::Opcodes::Yield => match vm.coroutine {
+            Some(id) => {
+                allocate(vm, heap_size(&y))?;
+                vm.coroutines[id].dest = window + instruction.r_x;
+                suspend(vm, id, CoroutineState::Suspended, y);
+            }
+            // Outside a coroutine, yield ends the thread's turn.
+            None => vm.slice = vm.quantum,
+        },
+        crate::opcodes::Opcodes::IsDone => match y {
+            Val::Coroutine(id) => {
+                let done = vm.coroutines[id].state == CoroutineState::Done;
+                vm.registers[window + instruction.r_x] = Val::Bool(done);
+            }
+            _ => return Err(VMError::runtime(format!("{} isn't a coroutine", y))),
+        },
+        crate::opcodes::Opcodes::Spawn => match &y {
+            Val::VMFunction(f) | Val::Closure(f, _) => {
+                check_arity(f, 0)?;
+                allocate(vm, slots(TASK_REGISTERS) + heap_size(&y))?;
+                let mut registers = vec![Val::Nil; TASK_REGISTERS];
+                registers[0] = y.clone();
+                vm.threads.push(Thread {
+                    context: Context::new(f.clone(), registers),
+                    coroutine: None,
+                    state: ThreadState::Runnable,
+                });
+                vm.registers[window + instruction.r_x] = Val::Num(vm.threads.len() as i32 - 1);
             }
-            crate::opcodes::Opcodes::IsNumber => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::Num(_) => Val::Bool(true),
-                    _ => Val::Bool(false),
+            _ => {
+                return Err(VMError::runtime(format!(
+                    "Can't spawn {}, which isn't a function",
+                    y
+                )))
+            }
+        },
+        crate::opcodes::Opcodes::MakeChannel => {
+            vm.channels.push(VecDeque::new());
+            vm.registers[window + instruction.r_x] = Val::Channel(vm.channels.len() - 1);
+        }
+        crate::opcodes::Opcodes::Send => match x {
+            Val::Channel(ch) => {
+                allocate(vm, slots(1) + heap_size(&y))?;
+                vm.channels[ch].push_back(y)
+            }
+            _ => {
+                return Err(VMError::runtime(format!(
+                    "Can't send on {}, which isn't a channel",
+                    x
+                )))
+            }
+        },
+        // Receiving from an empty channel blocks the thread, which tries again
+        // when it next gets a turn.
+        crate::opcodes::Opcodes::Receive => match y {
+            Val::Channel(ch) => match vm.channels[ch].pop_front() {
+                Some(v) => vm.registers[window + instruction.r_x] = v,
+                None => {
+                    vm.threads[vm.thread].state = ThreadState::Blocked(ch);
+                    vm.pc -= 1;
                 }
+            },
+            _ => {
+                return Err(VMError::runtime(format!(
+                    "Can't receive on {}, which isn't a channel",
+                    y
+                )))
             }
-            crate::opcodes::Opcodes::Greater => {
-                let num = Val::Bool(y.as_num() > z.as_num());
-                vm.registers[reg_window + instruction.r_x] = num;
-            }
-            crate::opcodes::Opcodes::Less => {
-                let num = Val::Bool(y.as_num() < z.as_num());
-                vm.registers[reg_window + instruction.r_x] = num;
-            }
-            crate::opcodes::Opcodes::LessEq => {
-                let num = Val::Bool(y.as_num() <= z.as_num());
-                vm.registers[reg_window + instruction.r_x] = num;
-            }
-            crate::opcodes::Opcodes::Return => {
-                let act = vm.stack.pop().unwrap();
-                *function = act.fun;
-                i = act.program_counter;
-                vm.registers[act.dest] = vm.registers[reg_window + instruction.r_x].clone();
-                reg_window = act.register_window;
-            }
-            crate::opcodes::Opcodes::Call => match y {
-                Val::VMFunction(f) => {
-                    let act = Activation {
-                        dest: reg_window + instruction.r_x,
-                        register_window: reg_window,
-                        program_counter: i,
-                        fun: function.clone(),
-                    };
-                    reg_window += instruction.r_z - instruction.r_y - 1;
-                    vm.stack.push(act);
-                    reg_window += instruction.r_y;
-                    *function = f.clone();
-                    i = 0;
+        },
+        crate::opcodes::Opcodes::ReadLine
+        | crate::opcodes::Opcodes::ReadNumber
+        | crate::opcodes::Opcodes::Read
+        | crate::opcodes::Opcodes::PortReadLine
+        | crate::opcodes::Opcodes::PortRead => {
+            let read = match instruction.opcode {
+                crate::opcodes::Opcodes::ReadLine => {
+                    input::read(&mut *vm.input, &mut vm.unread, Reading::Line)?
                 }
-                Val::Closure(f, _) => {
-                    let act = Activation {
-                        dest: reg_window + instruction.r_x,
-                        register_window: reg_window,
-                        program_counter: i,
-                        fun: function.clone(),
-                    };
-                    reg_window += instruction.r_z - instruction.r_y - 1;
-                    vm.stack.push(act);
-                    reg_window += instruction.r_y;
-                    *function = f.clone();
-                    i = 0;
+                crate::opcodes::Opcodes::ReadNumber => {
+                    input::read(&mut *vm.input, &mut vm.unread, Reading::Number)?
                 }
-                _ => {
-                    panic!("Can't Call something that isn't a function")
+                crate::opcodes::Opcodes::Read => {
+                    input::read(&mut *vm.input, &mut vm.unread, Reading::Datum)?
                 }
-            },
-            crate::opcodes::Opcodes::TailCall => match x {
-                Val::VMFunction(f) => {
-                    for i in 0..(instruction.r_y - instruction.r_x + 1) {
-                        vm.registers
-                            .swap(reg_window + i, reg_window + i + instruction.r_x);
+                crate::opcodes::Opcodes::PortReadLine => ports::read(vm, &y, Reading::Line)?,
+                _ => ports::read(vm, &y, Reading::Datum)?,
+            };
+            match read {
+                Some(v) => {
+                    allocate(vm, heap_size(&v))?;
+                    vm.registers[window + instruction.r_x] = v;
+                }
+                // Nothing to read yet; the instruction runs again once the
+                // program is resumed.
+                None => {
+                    vm.pc -= 1;
+                    return Ok(Status::BlockedOnInput);
+                }
+            }
+        }
+        crate::opcodes::Opcodes::OpenInput | crate::opcodes::Opcodes::OpenOutput => {
+            let writing = instruction.opcode == crate::opcodes::Opcodes::OpenOutput;
+            vm.registers[window + instruction.r_x] = ports::open(vm, &y, writing)?;
+        }
+        crate::opcodes::Opcodes::ClosePort => ports::close(vm, &x)?,
+        crate::opcodes::Opcodes::PortWrite | crate::opcodes::Opcodes::PortWriteLine => {
+            let newline = instruction.opcode == crate::opcodes::Opcodes::PortWriteLine;
+            ports::write(vm, &x, &y, newline)?;
+        }
+        crate::opcodes::Opcodes::IsEof => {
+            vm.registers[window + instruction.r_x] = Val::Bool(matches!(y, Val::Eof));
+        }
+        crate::opcodes::Opcodes::TailCall => {
+            let nargs = argument_count(instruction.r_x, instruction.r_y)?;
+            match x {
+                // The function and its arguments move down to the base of the
+                // current window, which the callee reuses, so a loop of tail
+                // calls runs in constant stack and register space. Moving them
+                // in ascending order never overwrites one not yet moved.
+                Val::VMFunction(f) | Val::Closure(f, _) => {
+                    check_arity(&f, nargs)?;
+                    register(vm, window + instruction.r_y)?;
+                    allocate(vm, rest_size(&f, nargs))?;
+                    for r in window..=window + nargs {
+                        let arg =
+                            std::mem::replace(&mut vm.registers[r + instruction.r_x], Val::Nil);
+                        vm.registers[r] = arg;
                     }
+                    collect_rest(vm, &f, nargs);
 
-                    *function = f.clone();
-                    i = 0;
+                    vm.func = f;
+                    vm.pc = 0;
+                }
+                Val::Continuation(k) => {
+                    let arg = window + instruction.r_x + 1;
+                    throw(vm, &k, nargs, arg)?
                 }
-                Val::Closure(f, _) => {
-                    for i in 0..(instruction.r_y - instruction.r_x + 1) {
-                        vm.registers
-                            .swap(reg_window + i, reg_window + i + instruction.r_x);
+                Val::Native(native) => {
+                    let args = window + instruction.r_x + 1..window + instruction.r_y + 1;
+                    let result = call_native(vm, &native, args)?;
+                    if return_value(vm, result)? == Status::Halted {
+                        return Ok(Status::Halted);
                     }
-
-                    *function = f.clone();
-                    i = 0;
                 }
                 _ => {
-                    panic!("Can't Call something that isn't a function got {:?}", x)
-                }
-            },
-            crate::opcodes::Opcodes::Cons => {
-                vm.registers[reg_window + instruction.r_x] =
-                    Val::Cons(Box::new(y.clone()), Box::new(z.clone()));
-            }
-            crate::opcodes::Opcodes::Car => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::Cons(x, _) => *x.clone(),
-                    _ => panic!("attempted to car: {}", x),
+                    return Err(VMError::runtime(format!(
+                        "Can't call {}, which isn't a function",
+                        x
+                    )))
                 }
             }
-            crate::opcodes::Opcodes::Cdr => {
-                vm.registers[reg_window + instruction.r_x] = match y {
-                    Val::Cons(_, xs) => *xs.clone(),
-                    _ => panic!("attempted to cdr: {}", y),
-                }
+        }
+        crate::opcodes::Opcodes::Cons => {
+            allocate(vm, slots(2) + heap_size(&y) + heap_size(&z))?;
+            vm.registers[vm.reg_window + instruction.r_x] =
+                Val::Cons(Box::new(y.clone()), Box::new(z.clone()));
+        }
+        crate::opcodes::Opcodes::Car => {
+            let car = match y {
+                Val::Cons(x, _) => *x,
+                _ => return Err(VMError::runtime(format!("attempted to car: {}", y))),
+            };
+            store(vm, window + instruction.r_x, car)?;
+        }
+        crate::opcodes::Opcodes::Cdr => {
+            let cdr = match y {
+                Val::Cons(_, xs) => *xs,
+                _ => return Err(VMError::runtime(format!("attempted to cdr: {}", y))),
+            };
+            store(vm, window + instruction.r_x, cdr)?;
+        }
+        crate::opcodes::Opcodes::MakeClosure => match y {
+            Val::VMFunction(f) => {
+                allocate(vm, slots(instruction.r_z))?;
+                vm.registers[vm.reg_window + instruction.r_x] =
+                    Val::Closure(f.clone(), vec![Val::Nil; instruction.r_z]);
             }
-            crate::opcodes::Opcodes::MakeClosure => match y {
-                Val::VMFunction(f) => {
-                    vm.registers[reg_window + instruction.r_x] =
-                        Val::Closure(f.clone(), vec![Val::Nil; instruction.r_z]);
-                }
-                _ => panic!("Attempted to make a closure without a function"),
-            },
-            crate::opcodes::Opcodes::SetClSlot => match x {
-                Val::Closure(f, v) => {
-                    let mut new_v = v.clone();
-                    let new_f = f.clone();
-                    new_v[instruction.r_z] = y.clone();
-                    vm.registers[instruction.r_x + reg_window] = Val::Closure(new_f, new_v);
-                }
-                _ => panic!("Attempted to set a non closure"),
-            },
-            crate::opcodes::Opcodes::GetClSlot => match y {
-                Val::Closure(_, v) => {
-                    vm.registers[reg_window + instruction.r_x] = v[instruction.r_z].clone()
-                }
-                _ => panic!("Attempted to set a non closure"),
-            },
-            crate::opcodes::Opcodes::SetCar => {
-                let x = vm.registers.get_mut(instruction.r_x).unwrap();
-                match x {
-                    Val::Cons(hd, _) => **hd = y.clone(),
-                    _ => panic!("error"),
-                }
-                ()
+            _ => {
+                return Err(VMError::runtime(
+                    "Attempted to make a closure without a function",
+                ))
             }
-            crate::opcodes::Opcodes::SetCdr => {
-                let x = vm.registers.get_mut(instruction.r_x).unwrap();
-                match x {
-                    Val::Cons(_, tail) => **tail = y.clone(),
-                    _ => panic!("error"),
-                }
-                ()
+        },
+        crate::opcodes::Opcodes::SetClSlot => match x {
+            Val::Closure(f, v) => {
+                allocate(vm, slots(v.len()) + heap_size(&y))?;
+                let mut new_v = v.clone();
+                let new_f = f.clone();
+                *new_v
+                    .get_mut(instruction.r_z)
+                    .ok_or_else(|| closure_slot_error(instruction.r_z))? = y.clone();
+                vm.registers[instruction.r_x + vm.reg_window] = Val::Closure(new_f, new_v);
             }
-            crate::opcodes::Opcodes::NotEqual => {}
-            crate::opcodes::Opcodes::Assert => {
-                vm.test_suite
-                    .assert(vm.literals[instruction.slot].as_string(), x);
+            _ => return Err(VMError::runtime("Attempted to set a non closure")),
+        },
+        crate::opcodes::Opcodes::GetClSlot => match y {
+            Val::Closure(_, v) => {
+                let value = v
+                    .get(instruction.r_z)
+                    .ok_or_else(|| closure_slot_error(instruction.r_z))?;
+                store(vm, window + instruction.r_x, value.clone())?
             }
-            crate::opcodes::Opcodes::IDiv => {
-                let num = Val::to_num(Val::as_num(&y) / Val::as_num(&z));
-                vm.registers[reg_window + instruction.r_x] = num;
+            _ => return Err(VMError::runtime("Attempted to read a non closure")),
+        },
+        crate::opcodes::Opcodes::SetCar => {
+            allocate(vm, heap_size(&y))?;
+            let x = &mut vm.registers[vm.reg_window + instruction.r_x];
+            match x {
+                Val::Cons(hd, _) => **hd = y.clone(),
+                _ => return Err(VMError::runtime(format!("attempted to set-car!: {}", x))),
             }
-            crate::opcodes::Opcodes::Pair => {
-                todo!()
+        }
+        crate::opcodes::Opcodes::SetCdr => {
+            allocate(vm, heap_size(&y))?;
+            let x = &mut vm.registers[vm.reg_window + instruction.r_x];
+            match x {
+                Val::Cons(_, tail) => **tail = y.clone(),
+                _ => return Err(VMError::runtime(format!("attempted to set-cdr!: {}", x))),
             }
-            crate::opcodes::Opcodes::Error => {
-                todo!()
+        }
+        crate::opcodes::Opcodes::NotEqual => {}
+        crate::opcodes::Opcodes::Assert => {
+            if let Some(v) = vm.literals.get(instruction.slot) {
+                vm.test_suite.assert(v.as_string()?, x);
             }
         }
+        crate::opcodes::Opcodes::IDiv => {
+            let num = Val::to_num(divide(y.as_num()?, z.as_num()?)?);
+            vm.registers[vm.reg_window + instruction.r_x] = num;
+        }
+        crate::opcodes::Opcodes::Pair => {
+            vm.registers[vm.reg_window + instruction.r_x] = match y {
+                Val::Cons(_, _) => Val::Bool(true),
+                _ => Val::Bool(false),
+            }
+        }
+        crate::opcodes::Opcodes::Error => return Err(VMError::runtime(x.to_string())),
+    }
+    if let Some(tracer) = vm.tracer.as_mut() {
+        if let Some(line) = tracer.pending.take() {
+            tracer.finish(line, &vm.registers[window..]);
+        }
+    }
+    if let Some(profiler) = vm.profiler.as_mut() {
+        // Calls to primitives don't enter a function, and a tail call to a
+        // primitive returns.
+        let entered = vm.stack.len() > depth;
+        match instruction.opcode {
+            _ if throws || vm.coroutine != coroutine => profiler.sync(
+                vm.stack
+                    .iter()
+                    .map(|activation| activation.fun.name.as_str())
+                    .chain(std::iter::once(vm.func.name.as_str())),
+            ),
+            crate::opcodes::Opcodes::Call
+            | crate::opcodes::Opcodes::Apply
+            | crate::opcodes::Opcodes::CallCC
+                if !entered => {}
+            crate::opcodes::Opcodes::TailCall if vm.stack.len() < depth => {
+                profiler.after(crate::opcodes::Opcodes::Return, &vm.func.name)
+            }
+            opcode => profiler.after(opcode, &vm.func.name),
+        }
+    }
+    if let Some(coverage) = vm.coverage.as_mut() {
+        // Entering a function, or a fresh coroutine, leaves it at its start,
+        // where calls to primitives and continuations never do.
+        let enters = matches!(
+            instruction.opcode,
+            crate::opcodes::Opcodes::Call
+                | crate::opcodes::Opcodes::Apply
+                | crate::opcodes::Opcodes::CallCC
+                | crate::opcodes::Opcodes::TailCall
+                | crate::opcodes::Opcodes::Resume
+        );
+        if enters && vm.pc == 0 {
+            coverage.enter(&vm.func);
+        }
+    }
+    Ok(Status::Running)
+}
+
+fn closure_slot_error(slot: usize) -> VMError {
+    VMError::runtime(format!("Closure slot {} is out of range", slot))
+}
+
+fn register(vm: &VMState, r: usize) -> Result<Val, VMError> {
+    vm.registers
+        .get(r)
+        .cloned()
+        .ok_or_else(|| VMError::runtime(format!("Register {} is out of range", r)))
+}
+
+// Calls the function in register `r_f` of the current window with the
+// `nargs` arguments after it, leaving its result in register `r_dest`.
+fn call(
+    vm: &mut VMState,
+    callee: &Val,
+    r_dest: usize,
+    r_f: usize,
+    nargs: usize,
+) -> Result<(), VMError> {
+    match callee {
+        Val::VMFunction(f) | Val::Closure(f, _) => {
+            check_arity(f, nargs)?;
+            allocate(vm, rest_size(f, nargs))?;
+            // Running out of registers for the callee's window is as much a
+            // stack overflow as running out of activations.
+            let end = vm.reg_window + r_f + f.registers_used();
+            if vm.stack.len() >= vm.max_depth || end > vm.registers.len() {
+                return Err(VMError::runtime(format!(
+                    "stack overflow in {} at depth {}",
+                    f.name,
+                    vm.stack.len() + 1
+                )));
+            }
+            let act = Activation {
+                dest: vm.reg_window + r_dest,
+                register_window: vm.reg_window,
+                program_counter: vm.pc,
+                fun: vm.func.clone(),
+            };
+            vm.stack.push(act);
+            vm.reg_window += r_f;
+            collect_rest(vm, f, nargs);
+            vm.func = f.clone();
+            vm.pc = 0;
+            Ok(())
+        }
+        Val::Continuation(k) => throw(vm, k, nargs, vm.reg_window + r_f + 1),
+        Val::Native(native) => {
+            let first = vm.reg_window + r_f + 1;
+            let result = call_native(vm, native, first..first + nargs)?;
+            store(vm, vm.reg_window + r_dest, result)
+        }
+        _ => Err(VMError::runtime(format!(
+            "Can't call {}, which isn't a function",
+            callee
+        ))),
+    }
+}
+
+// Returns from the current function. Returning from the outermost function
+// of a coroutine finishes it, and of a spawned thread ends the thread.
+fn return_value(vm: &mut VMState, value: Val) -> Result<Status, VMError> {
+    allocate(vm, heap_size(&value))?;
+    match vm.stack.pop() {
+        Some(act) => {
+            vm.func = act.fun;
+            vm.pc = act.program_counter;
+            vm.registers[act.dest] = value;
+            vm.reg_window = act.register_window;
+        }
+        None => match vm.coroutine {
+            Some(id) => suspend(vm, id, CoroutineState::Done, value),
+            None if vm.thread != 0 => return Ok(Status::Halted),
+            None => return Err(VMError::runtime("Return with an empty call stack")),
+        },
+    }
+    Ok(Status::Running)
+}
+
+fn call_native(vm: &mut VMState, native: &Native, args: Range<usize>) -> Result<Val, VMError> {
+    let nargs = args.len();
+    if nargs != native.arity {
+        return Err(VMError::runtime(format!(
+            "{} expects {} argument{}, got {}",
+            native.name,
+            native.arity,
+            if native.arity == 1 { "" } else { "s" },
+            nargs
+        )));
+    }
+    let args = vm
+        .registers
+        .get(args)
+        .ok_or_else(|| VMError::runtime("Arguments are out of range of the registers"))?
+        .to_vec();
+    (native.function)(vm, &args)
+}
+
+// Continues the computation captured in `k`, with the value in register `arg`
+// as the result of its `callcc`.
+fn throw(vm: &mut VMState, k: &Continuation, nargs: usize, arg: usize) -> Result<(), VMError> {
+    if nargs != 1 {
+        return Err(VMError::runtime(format!(
+            "A continuation expects 1 argument, got {}",
+            nargs
+        )));
+    }
+    if (k.thread, k.coroutine) != (vm.thread, vm.coroutine) {
+        return Err(VMError::runtime(
+            "Can't invoke a continuation captured in another coroutine or thread",
+        ));
+    }
+    // Going back to where it was captured would unwind the Rust caller's
+    // frames along with the VM's.
+    if vm
+        .rust_call_depth
+        .is_some_and(|depth| k.stack.len() <= depth)
+    {
+        return Err(VMError::runtime(
+            "Can't invoke a continuation captured outside the current call from Rust",
+        ));
+    }
+    let value = register(vm, arg)?;
+    allocate(vm, k.registers.iter().map(heap_size).sum())?;
+    vm.registers[..k.registers.len()].clone_from_slice(&k.registers);
+    vm.stack = k.stack.clone();
+    vm.func = k.fun.clone();
+    vm.pc = k.program_counter;
+    vm.reg_window = k.register_window;
+    vm.registers[k.dest] = value;
+    Ok(())
+}
+
+enum Saved {
+    Coroutine(usize),
+    Thread(usize),
+}
+
+// Exchanges the running context with a saved one.
+fn switch(vm: &mut VMState, saved: Saved) {
+    let context = match saved {
+        Saved::Coroutine(id) => &mut vm.coroutines[id].context,
+        Saved::Thread(id) => &mut vm.threads[id].context,
+    };
+    std::mem::swap(&mut vm.stack, &mut context.stack);
+    std::mem::swap(&mut vm.func, &mut context.fun);
+    std::mem::swap(&mut vm.pc, &mut context.program_counter);
+    std::mem::swap(&mut vm.reg_window, &mut context.register_window);
+    std::mem::swap(&mut vm.registers, &mut context.registers);
+}
+
+// Stops running coroutine `id`, returning `value` to whoever resumed it.
+fn suspend(vm: &mut VMState, id: usize, state: CoroutineState, value: Val) {
+    switch(vm, Saved::Coroutine(id));
+    let co = &mut vm.coroutines[id];
+    co.state = state;
+    vm.coroutine = co.resumer;
+    vm.registers[co.resumer_dest] = value;
+}
+
+// Gives the next thread able to run a turn once the running one has
+// finished, blocked or used up its quantum. Threads blocked on a channel can
+// run again once it has something to receive.
+fn schedule(vm: &mut VMState, status: Status) -> Result<Status, VMError> {
+    if status == Status::BlockedOnInput {
+        return Ok(status);
+    }
+    let current = vm.thread;
+    if status == Status::Halted {
+        vm.threads[current].state = ThreadState::Done;
+    }
+    vm.slice += 1;
+    if vm.threads[current].state == ThreadState::Runnable
+        && (vm.threads.len() == 1 || vm.slice < vm.quantum)
+    {
+        return Ok(Status::Running);
+    }
+    let n = vm.threads.len();
+    let next = (1..=n)
+        .map(|i| (current + i) % n)
+        .find(|&t| match vm.threads[t].state {
+            ThreadState::Runnable => true,
+            ThreadState::Blocked(ch) => !vm.channels[ch].is_empty(),
+            ThreadState::Done => false,
+        });
+    match next {
+        Some(next) => {
+            if next != current {
+                vm.threads[current].coroutine = vm.coroutine;
+                switch(vm, Saved::Thread(current));
+                switch(vm, Saved::Thread(next));
+                vm.coroutine = vm.threads[next].coroutine;
+                vm.thread = next;
+            }
+            vm.threads[next].state = ThreadState::Runnable;
+            vm.slice = 0;
+            Ok(Status::Running)
+        }
+        None if vm.threads.iter().all(|t| t.state == ThreadState::Done) => Ok(Status::Halted),
+        None => Err(deadlock(vm)),
+    }
+}
+
+fn deadlock(vm: &VMState) -> VMError {
+    let mut message = "deadlock, every thread is blocked:".to_string();
+    for (i, thread) in vm.threads.iter().enumerate() {
+        if let ThreadState::Blocked(ch) = thread.state {
+            let (fun, pc) = match i == vm.thread {
+                true => (&vm.func, vm.pc),
+                false => (&thread.context.fun, thread.context.program_counter),
+            };
+            message.push_str(&format!(
+                "\n  thread {} in {} at {} receiving on #<channel {}>",
+                i, fun.name, pc, ch
+            ));
+        }
+    }
+    VMError::runtime(message)
+}
+
+fn check_arity(f: &VMFunction, args: usize) -> Result<(), VMError> {
+    let arity = f.arity as usize;
+    if args == arity || (f.variadic && args >= arity) {
+        return Ok(());
+    }
+    Err(VMError::runtime(format!(
+        "{} expects {}{} argument{}, got {}",
+        f.name,
+        if f.variadic { "at least " } else { "" },
+        f.arity,
+        if f.arity == 1 { "" } else { "s" },
+        args
+    )))
+}
+
+// The number of arguments to a function in register `function` when the
+// last one is in register `last`.
+fn argument_count(function: usize, last: usize) -> Result<usize, VMError> {
+    last.checked_sub(function).ok_or_else(|| {
+        VMError::runtime(format!(
+            "The arguments can't end in r{}, before the function in r{}",
+            last, function
+        ))
+    })
+}
+
+// The memory `collect_rest` needs for the list.
+fn rest_size(f: &VMFunction, nargs: usize) -> usize {
+    match f.variadic {
+        true => slots(2 * (nargs - f.arity as usize)),
+        false => 0,
+    }
+}
+
+// Replaces the arguments of a variadic function after its fixed ones with a
+// list of them in the first register after the fixed ones. The function's
+// window has just been set up with its `nargs` arguments.
+fn collect_rest(vm: &mut VMState, f: &VMFunction, nargs: usize) {
+    if !f.variadic {
+        return;
+    }
+    let first = vm.reg_window + f.arity as usize + 1;
+    let mut rest = Val::EmptyList;
+    for r in (first..=vm.reg_window + nargs).rev() {
+        let arg = std::mem::replace(&mut vm.registers[r], Val::Nil);
+        rest = Val::Cons(Box::new(arg), Box::new(rest));
+    }
+    vm.registers[first] = rest;
+}
+
+// Stores a value in register `r`. Values are copied when they are stored,
+// so this charges for the heap the copy owns.
+fn store(vm: &mut VMState, r: usize, value: Val) -> Result<(), VMError> {
+    allocate(vm, heap_size(&value))?;
+    set_register(vm, r, value)
+}
+
+fn set_register(vm: &mut VMState, r: usize, value: Val) -> Result<(), VMError> {
+    *vm.registers
+        .get_mut(r)
+        .ok_or_else(|| VMError::runtime(format!("Register {} is out of range", r)))? = value;
+    Ok(())
+}
+
+fn divide(n: i32, d: i32) -> Result<i32, VMError> {
+    n.checked_div(d)
+        .ok_or_else(|| VMError::runtime(format!("Can't divide {} by {}", n, d)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{loader::load_str, output::Buffer, vmstate::init_vm_state};
+
+    // Runs a module, checking that the call stack and register window never
+    // grow past the given limits, and returns the final state.
+    fn run_bounded(source: &str, max_depth: usize, max_window: usize) -> VMState {
+        let mut vm = init_vm_state();
+        let module = load_str(source, &mut vm).unwrap();
+        start(&mut vm, module);
+        while step(&mut vm).unwrap() == Status::Running {
+            assert!(
+                vm.stack.len() <= max_depth,
+                "stack grew to {}",
+                vm.stack.len()
+            );
+            assert!(
+                vm.reg_window <= max_window,
+                "window moved to {}",
+                vm.reg_window
+            );
+        }
+        vm
+    }
+
+    #[test]
+    fn tail_calls_to_functions_run_in_constant_space() {
+        let vm = run_bounded(
+            ".load module 4
+.load 0 function 1 8 loop
+loadliteral 2 0
+= 3 1 2
+if 3
+return 1
+getglobal 2 string 4 108 111 111 112
+loadliteral 3 1
+- 3 1 3
+tailcall 2 3
+setglobal 0 string 4 108 111 111 112
+loadliteral 1 1000000
+call 2 0 1
+",
+            1,
+            0,
+        );
+        assert_eq!(vm.registers[2], Val::Num(0));
+    }
+
+    #[test]
+    fn tail_calls_to_closures_run_in_constant_space() {
+        let vm = run_bounded(
+            ".load module 6
+.load 0 function 1 8 loop
+getclslot 2 0 0
+loadliteral 3 0
+= 4 1 3
+if 4
+return 1
+- 5 1 2
+mov 4 0
+tailcall 4 5
+mkclosure 1 0 1
+loadliteral 2 1
+setclslot 1 2 0
+loadliteral 2 1000000
+call 3 1 2
+",
+            1,
+            1,
+        );
+        assert_eq!(vm.registers[3], Val::Num(0));
+    }
+
+    #[test]
+    fn tail_calls_check_arity() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 3
+.load 0 function 2 1 f
+return 1
+loadliteral 1 1
+tailcall 0 1
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("f expects 2 arguments, got 1"))
+        );
+    }
+
+    #[test]
+    fn out_of_fuel_can_be_refilled_and_resumed() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 4
+loadliteral 1 0
+loadliteral 2 1
++ 1 1 2
+goto -1
+",
+            &mut vm,
+        )
+        .unwrap();
+        vm.fuel = Some(10);
+        assert_eq!(run(&mut vm, module), Ok(Status::OutOfFuel));
+        assert_eq!(vm.registers[1], Val::Num(4));
+        vm.fuel = Some(10);
+        assert_eq!(resume(&mut vm), Ok(Status::OutOfFuel));
+        assert_eq!(vm.registers[1], Val::Num(9));
+    }
+
+    #[test]
+    fn times_out_at_the_deadline() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 1
+goto 0
+",
+            &mut vm,
+        )
+        .unwrap();
+        vm.deadline = Some(Instant::now());
+        assert_eq!(run(&mut vm, module), Ok(Status::TimedOut));
+    }
+
+    #[test]
+    fn times_out_when_run_in_small_slices() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 1
+goto 0
+",
+            &mut vm,
+        )
+        .unwrap();
+        start(&mut vm, module);
+        vm.deadline = Some(Instant::now());
+        assert_eq!(
+            run_until(&mut vm, Some(10), |_| false),
+            Ok(Status::TimedOut)
+        );
+    }
+
+    #[test]
+    fn calls_give_the_callee_a_window_at_the_function() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 9
+loadliteral 1 100
+loadliteral 2 200
+.load 3 function 2 2 f
+- 3 1 2
+return 3
+loadliteral 4 10
+loadliteral 5 3
+call 6 3 5
+.load 7 function 0 2 g
+loadliteral 1 42
+return 1
+call 8 7 7
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        let registers: Vec<Val> = [1, 2, 4, 5, 6, 8]
+            .iter()
+            .map(|&r| vm.registers[r].clone())
+            .collect();
+        assert_eq!(registers, [100, 200, 10, 3, 7, 42].map(Val::Num).to_vec());
+    }
+
+    #[test]
+    fn calls_check_their_register_ranges() {
+        for call in ["call 1 5 2", "tailcall 5 2"] {
+            let mut vm = init_vm_state();
+            let source = format!(".load module 2\n{}\nhalt\n", call);
+            let module = load_str(&source, &mut vm).unwrap();
+            assert_eq!(
+                run(&mut vm, module),
+                Err(VMError::runtime(
+                    "The arguments can't end in r2, before the function in r5"
+                ))
+            );
+        }
+    }
+
+    fn list(items: &[i32]) -> Val {
+        items.iter().rev().fold(Val::EmptyList, |rest, &n| {
+            Val::Cons(Box::new(Val::Num(n)), Box::new(rest))
+        })
+    }
+
+    #[test]
+    fn collects_extra_arguments_into_a_list() {
+        // rest returns the list of its arguments after the first.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 9
+.load 0 function 1+ 2 rest
+mov 3 2
+return 3
+mov 10 0
+loadliteral 11 1
+loadliteral 12 2
+loadliteral 13 3
+call 1 10 13
+call 2 10 11
+call 3 10 10
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("rest expects at least 1 argument, got 0"))
+        );
+        assert_eq!(vm.registers[1], list(&[2, 3]));
+        assert_eq!(vm.registers[2], list(&[]));
+    }
+
+    #[test]
+    fn applies_functions_to_lists_of_arguments() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 9
+.load 1 function 1+ 2 rest
+mov 3 2
+return 3
+loadliteral 2 1
+loadliteral 3 2
+loadliteral 4 3
+call 5 1 4
+apply 6 1 5
+loadliteral 2 emptylist
+apply 7 1 2
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("rest expects at least 1 argument, got 0"))
+        );
+        assert_eq!(vm.registers[5], list(&[2, 3]));
+        assert_eq!(vm.registers[6], list(&[3]));
+    }
+
+    #[test]
+    fn escapes_with_a_continuation() {
+        // escape returns 42 through its continuation, skipping its own return.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 3
+.load 0 function 1 3 escape
+loadliteral 2 42
+tailcall 1 2
+return 1
+callcc 1 0
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        assert_eq!(vm.registers[1], Val::Num(42));
+    }
+
+    #[test]
+    fn reenters_a_continuation() {
+        // save stores its continuation in k and returns 0; invoking k with
+        // n + 1 returns from the callcc again until n reaches 3.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 11
+.load 0 function 1 3 save
+setglobal 1 string 1 107
+loadliteral 2 0
+return 2
+callcc 1 0
+loadliteral 2 3
+< 3 1 2
+if 3
+goto 2
+halt
+loadliteral 4 1
++ 5 1 4
+getglobal 4 string 1 107
+call 7 4 5
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        assert_eq!(vm.registers[1], Val::Num(3));
+    }
+
+    #[test]
+    fn continuations_take_one_argument() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 3
+.load 0 function 1 1 twice
+tailcall 1 3
+callcc 1 0
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("A continuation expects 1 argument, got 2"))
+        );
+    }
+
+    #[test]
+    fn resumes_coroutines_until_they_are_done() {
+        // counter yields 0, 1, 2, ...; once yields 7, then returns what it is
+        // resumed with.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 13
+.load 0 function 1 5 counter
+loadliteral 2 0
+loadliteral 3 1
+yield 4 2
++ 2 2 3
+goto -2
+coroutine 1 0
+resume 2 1 0
+resume 3 1 0
+resume 4 1 0
+done? 5 1
+.load 6 function 1 3 once
+loadliteral 2 7
+yield 3 2
+return 3
+coroutine 7 6
+resume 8 7 0
+loadliteral 9 9
+resume 10 7 9
+done? 11 7
+resume 12 7 9
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("Can't resume a finished coroutine"))
+        );
+        let registers: Vec<String> = [2, 3, 4, 5, 8, 10, 11]
+            .iter()
+            .map(|&r| vm.registers[r].to_string())
+            .collect();
+        assert_eq!(registers, ["0", "1", "2", "false", "7", "9", "true"]);
+    }
+
+    #[test]
+    fn escapes_with_a_continuation_inside_a_coroutine() {
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 4
+.load 0 function 1 4 body
+.load 1 function 1 3 escape
+loadliteral 2 42
+tailcall 1 2
+return 1
+callcc 2 1
+yield 3 2
+return 3
+coroutine 1 0
+resume 2 1 0
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Ok(Status::Halted));
+        assert_eq!(vm.registers[2], Val::Num(42));
+    }
+
+    const CROSS_CONTEXT: &str =
+        "Can't invoke a continuation captured in another coroutine or thread";
+
+    #[test]
+    fn keeps_continuations_out_of_coroutines() {
+        // The main program resumes a coroutine with its continuation, which
+        // the coroutine invokes.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 6
+.load 0 function 1 3 body
+loadliteral 2 5
+call 3 1 2
+return 3
+coroutine 1 0
+setglobal 1 string 1 99
+.load 2 function 1 3 enter
+getglobal 2 string 1 99
+resume 3 2 1
+return 3
+callcc 3 2
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Err(VMError::runtime(CROSS_CONTEXT)));
+    }
+
+    #[test]
+    fn keeps_continuations_inside_their_coroutine() {
+        // A coroutine yields its continuation, which the main program invokes.
+        let mut vm = init_vm_state();
+        let module = load_str(
+            ".load module 6
+.load 0 function 1 3 body
+.load 2 function 1 2 give
+yield 2 1
+return 2
+callcc 3 2
+return 3
+coroutine 1 0
+resume 2 1 0
+loadliteral 3 5
+call 4 2 3
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, module), Err(VMError::runtime(CROSS_CONTEXT)));
+    }
+
+    // Runs `source` in a new VM and returns what it printed, and its error if
+    // it stopped with one.
+    fn run_printing(source: &str) -> (String, Option<VMError>) {
+        let mut vm = init_vm_state();
+        let printed = Buffer::new();
+        vm.output = Box::new(printed.clone());
+        let module = load_str(source, &mut vm).unwrap();
+        let error = run(&mut vm, module).err();
+        (printed.contents(), error)
+    }
+
+    #[test]
+    fn keeps_continuations_inside_their_thread() {
+        // The main program spawns a thread that invokes its continuation.
+        let (_, error) = run_printing(
+            ".load module 3
+.load 0 function 1 4 grab
+setglobal 1 string 1 107
+.load 2 function 0 4 other
+getglobal 1 string 1 107
+loadliteral 2 5
+call 3 1 2
+return 3
+spawn 3 2
+return 3
+callcc 1 0
+halt
+",
+        );
+        assert_eq!(error, Some(VMError::runtime(CROSS_CONTEXT)));
+    }
+
+    #[test]
+    fn passes_messages_between_threads() {
+        // producer sends 1, 2 and 3 on the channel in the global c.
+        let (printed, error) = run_printing(
+            ".load module 11
+.load 0 function 0 8 producer
+getglobal 1 string 1 99
+loadliteral 2 1
+send 1 2
+loadliteral 2 2
+send 1 2
+loadliteral 2 3
+send 1 2
+return 2
+channel 1
+setglobal 1 string 1 99
+spawn 2 0
+receive 3 1
+print 3
+receive 3 1
+print 3
+receive 3 1
+print 3
+halt
+",
+        );
+        assert_eq!(error, None);
+        assert_eq!(printed, "1\n2\n3\n");
+    }
+
+    #[test]
+    fn takes_turns_at_each_yield() {
+        let (printed, error) = run_printing(
+            ".load module 8
+.load 0 function 0 5 other
+loadliteral 1 1
+print 1
+yield 2 1
+print 1
+return 1
+spawn 1 0
+loadliteral 2 0
+print 2
+yield 3 2
+print 2
+yield 3 2
+halt
+",
+        );
+        assert_eq!(error, None);
+        assert_eq!(printed, "0\n1\n0\n1\n");
+    }
+
+    #[test]
+    fn reports_deadlocks() {
+        // waiter and the main program both wait on the same empty channel.
+        let (_, error) = run_printing(
+            ".load module 5
+.load 0 function 0 3 waiter
+getglobal 1 string 1 99
+receive 2 1
+return 2
+channel 1
+setglobal 1 string 1 99
+spawn 2 0
+receive 3 1
+",
+        );
+        assert_eq!(
+            error,
+            Some(VMError::runtime(
+                "deadlock, every thread is blocked:
+  thread 0 in module at 4 receiving on #<channel 0>
+  thread 1 in waiter at 1 receiving on #<channel 0>"
+            ))
+        );
+    }
+
+    #[test]
+    fn reports_running_out_of_registers_as_a_stack_overflow() {
+        // f and g call each other without end, each moving the window by 6 of
+        // the 8 registers they use, so the registers run out before max_depth.
+        let recurse = |other: u8| {
+            format!(
+                "loadliteral 2 1\n+ 7 1 2\ngetglobal 6 string 1 {}\ncall 3 6 7\nreturn 3\n",
+                other
+            )
+        };
+        let mut vm = init_vm_state();
+        let module = load_str(
+            &format!(
+                ".load module 8
+.load 0 function 1 5 f
+{}setglobal 0 string 1 102
+.load 0 function 1 5 g
+{}setglobal 0 string 1 103
+loadliteral 2 0
+getglobal 1 string 1 102
+call 3 1 2
+halt
+",
+                recurse(b'g'),
+                recurse(b'f')
+            ),
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut vm, module),
+            Err(VMError::runtime("stack overflow in f at depth 8333"))
+        );
+        assert_eq!(vm.location(), "g at 3");
+        let backtrace = vm.backtrace();
+        assert_eq!(backtrace.len(), 21);
+        assert_eq!(&backtrace[..2], ["#0 g at 3", "#1 f at 3"]);
+        assert_eq!(backtrace[10], "... 8313 more lines");
+        assert_eq!(backtrace[20], "#8332 module at 6");
+    }
+
+    #[test]
+    fn counts_copies_of_values_against_the_memory_limit() {
+        // Builds a list of 2000 numbers, then hands a copy of it down 3000
+        // frames of f, each of which copies it with mov.
+        let mut vm = init_vm_state();
+        vm.memory.limit = Some(4 << 20);
+        let module = load_str(
+            ".load module 17
+.load 0 function 2 10 f
+loadliteral 3 0
+= 4 2 3
+if 4
+return 3
+getglobal 5 string 1 102
+mov 6 1
+loadliteral 7 1
+- 7 2 7
+call 4 5 7
+return 4
+setglobal 0 string 1 102
+loadliteral 1 emptylist
+loadliteral 2 2000
+loadliteral 3 0
+loadliteral 4 1
+= 5 2 3
+if 5
+goto 4
+cons 1 2 1
+- 2 2 4
+goto -5
+getglobal 5 string 1 102
+mov 6 1
+loadliteral 7 3000
+call 8 5 7
+halt
+",
+            &mut vm,
+        )
+        .unwrap();
+        match run(&mut vm, module) {
+            Err(VMError::Runtime(message)) => assert!(
+                message.starts_with("out of memory: "),
+                "unexpected error: {}",
+                message
+            ),
+            result => panic!("expected running out of memory, got {:?}", result),
+        }
+        assert!(vm.stack.len() < 100, "ran {} frames deep", vm.stack.len());
+        assert!(vm.memory.peak <= 4 << 20);
+    }
+
+    #[test]
+    fn pairs_are_tested_and_changed_in_the_current_window() {
+        let vm = run_bounded(
+            ".load module 10
+.load 0 function 1 5 change
+loadliteral 2 9
+set-car! 1 2
+loadliteral 2 emptylist
+set-cdr! 1 2
+return 1
+loadliteral 1 1
+loadliteral 2 emptylist
+cons 3 1 2
+pair? 4 3
+pair? 5 2
+cons 3 1 3
+mov 7 0
+mov 8 3
+call 6 7 8
+",
+            1,
+            7,
+        );
+        assert_eq!(vm.registers[4], Val::Bool(true));
+        assert_eq!(vm.registers[5], Val::Bool(false));
+        let changed = Val::Cons(Box::new(Val::Num(9)), Box::new(Val::EmptyList));
+        assert_eq!(vm.registers[6], changed);
     }
 }
@@ -1,11 +1,91 @@
+#[allow(clippy::module_inception)]
 pub mod vmstack {
-    use crate::value::value::VMFunction;
+    use crate::value::value::{VMFunction, Val};
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Hash)]
     pub struct Activation {
         pub dest: usize,
         pub register_window: usize,
         pub program_counter: usize,
         pub fun: VMFunction,
     }
+
+    // The rest of a computation as captured by `callcc`: the call stack, the
+    // function and instruction to continue at, and the registers of every
+    // window in use. Invoking it puts them all back and delivers its argument
+    // to `dest`. Those belong to the thread and coroutine it was captured in,
+    // so it can only be invoked there.
+    #[derive(Debug, Clone, Hash)]
+    pub struct Continuation {
+        pub stack: Vec<Activation>,
+        pub fun: VMFunction,
+        pub program_counter: usize,
+        pub register_window: usize,
+        pub registers: Vec<Val>,
+        pub dest: usize,
+        pub thread: usize,
+        pub coroutine: Option<usize>,
+    }
+
+    // What a coroutine needs to run: its own call stack and registers, and
+    // where it is in them. While a coroutine runs, its `Context` holds the
+    // one of whoever resumed it instead.
+    #[derive(Debug, Clone)]
+    pub struct Context {
+        pub stack: Vec<Activation>,
+        pub fun: VMFunction,
+        pub program_counter: usize,
+        pub register_window: usize,
+        pub registers: Vec<Val>,
+    }
+
+    impl Context {
+        // A context that starts running `fun` from an empty call stack.
+        pub fn new(fun: VMFunction, registers: Vec<Val>) -> Self {
+            Context {
+                stack: Vec::new(),
+                fun,
+                program_counter: 0,
+                register_window: 0,
+                registers,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CoroutineState {
+        Fresh,
+        Suspended,
+        Running,
+        Done,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Coroutine {
+        pub context: Context,
+        pub state: CoroutineState,
+        // The register that receives the value it is resumed with.
+        pub dest: usize,
+        // The register of the resumer that receives what it yields or
+        // returns, and the coroutine the resumer was running in.
+        pub resumer_dest: usize,
+        pub resumer: Option<usize>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ThreadState {
+        Runnable,
+        // Waiting to receive on the channel with this index.
+        Blocked(usize),
+        Done,
+    }
+
+    // A green thread. The running thread's `Context` holds nothing useful;
+    // its state is in the VM.
+    #[derive(Debug, Clone)]
+    pub struct Thread {
+        pub context: Context,
+        pub coroutine: Option<usize>,
+        pub state: ThreadState,
+    }
 }
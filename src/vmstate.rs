@@ -1,17 +1,80 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
 
-use crate::value::value::{VMFunction, Val};
-use crate::vmstack::vmstack::Activation;
+use crate::coverage::Coverage;
+use crate::error::VMError;
+use crate::input::{Input, Stdin};
+use crate::loader;
+use crate::memory::Memory;
+use crate::output::{Output, Stdout};
+use crate::ports::Port;
+use crate::primitives::{define_primitives, Random};
+use crate::profile::Profiler;
+use crate::trace::Tracer;
+use crate::value::value::{Native, VMFunction, Val};
+use crate::vmrun::{self, Status};
+use crate::vmstack::vmstack::{Activation, Context, Coroutine, Thread, ThreadState};
 use colored::*;
 
-#[derive(Debug)]
 pub struct VMState {
     pub func: VMFunction,
+    pub pc: usize,
+    pub reg_window: usize,
     pub registers: Vec<Val>,
     pub globals: HashMap<Val, Val>,
     pub literals: Vec<Val>,
+    // The number of functions loaded, modules included, which is the `id`
+    // of the next one.
+    pub functions_loaded: usize,
     pub stack: Vec<Activation>,
+    // The most activations `stack` may hold before a call is a stack
+    // overflow.
+    pub max_depth: usize,
+    // How deep `stack` was when the innermost call from Rust, made with
+    // `VMState::call`, began, while one is running. Continuations captured
+    // outside that call can't be invoked inside it.
+    pub rust_call_depth: Option<usize>,
+    // The number of instructions `vmrun::run` may still execute, if limited,
+    // and the time by which it has to finish.
+    pub fuel: Option<u64>,
+    pub deadline: Option<Instant>,
+    // Functions and instruction indices `vmrun::run_until` stops before.
+    pub breakpoints: Vec<(String, usize)>,
+    pub memory: Memory,
+    pub coroutines: Vec<Coroutine>,
+    // The index in `coroutines` of the one running, if any.
+    pub coroutine: Option<usize>,
+    pub threads: Vec<Thread>,
+    // The index in `threads` of the one running; the main program is 0.
+    pub thread: usize,
+    pub channels: Vec<VecDeque<Val>>,
+    // The number of instructions a thread runs before another gets a turn,
+    // and the number it has run in this turn.
+    pub quantum: usize,
+    pub slice: usize,
     pub test_suite: Tester,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub output: Box<dyn Output>,
+    pub input: Box<dyn Input>,
+    // Input that has been read but not used by the instruction reading it.
+    pub unread: String,
+    // Files the program has opened, by the index in their port. Closed ones
+    // are None.
+    pub ports: Vec<Option<Port>>,
+    // The directory, with its path resolved, under which the program may
+    // open files. Without one it may open none.
+    pub fs_root: Option<PathBuf>,
+    // When the VM was created, which `clock-ms` counts from, the generator
+    // behind `random`, and what `command-line-arguments` returns.
+    pub clock: Instant,
+    pub random: Random,
+    pub args: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -22,58 +85,256 @@ pub struct Tester {
 }
 
 impl Tester {
-    pub fn check(&mut self, s: String, v: Val) -> () {
+    pub fn check(&mut self, s: String, v: Val) {
         self.checkv = (v, s)
     }
-    pub fn expect(&mut self, _s: String, v: Val) -> () {
+    pub fn expect(&mut self, _s: String, v: Val, out: &mut dyn Output) -> io::Result<()> {
         self.tests += 1;
         if v != self.checkv.0 {
-            println!("Got {:?}: Expected: {:?}", v, self.checkv.0);
-            return;
+            let message = format!("Got {:?}: Expected: {:?}", v, self.checkv.0);
+            if out.color() {
+                return writeln!(out, "{}", message.red());
+            }
+            return writeln!(out, "{}", message);
         }
         self.passed += 1;
+        Ok(())
     }
-    pub fn assert(&mut self, _s: String, v: Val) -> () {
+    pub fn assert(&mut self, _s: String, v: Val) {
         self.tests += 1;
         if v.as_bool() {
             self.passed += 1;
         }
     }
-    pub fn report_tests(&self) -> () {
+    pub fn tests(&self) -> u32 {
+        self.tests
+    }
+    pub fn passed(&self) -> u32 {
+        self.passed
+    }
+    pub fn summary(&self) -> String {
         if self.passed == self.tests {
-            println!("{}", "All tests passed".green())
+            "All tests passed".to_string()
         } else {
-            println!("{}", "Some tests failed".red())
+            "Some tests failed".to_string()
         }
     }
+    pub fn report_tests(&self, out: &mut dyn Output) -> io::Result<()> {
+        if !out.color() {
+            writeln!(out, "{}", self.summary())
+        } else if self.passed == self.tests {
+            writeln!(out, "{}", self.summary().green())
+        } else {
+            writeln!(out, "{}", self.summary().red())
+        }
+    }
+}
+
+// The size of the register area of each coroutine and spawned thread.
+pub const TASK_REGISTERS: usize = 4096;
+
+pub const DEFAULT_QUANTUM: usize = 100;
+
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+// The number of lines kept at each end of a long backtrace.
+const BACKTRACE_ENDS: usize = 10;
+
+pub fn main_thread(func: &VMFunction) -> Thread {
+    Thread {
+        context: Context::new(func.clone(), Vec::new()),
+        coroutine: None,
+        state: ThreadState::Runnable,
+    }
 }
 
 pub fn init_vm_state() -> VMState {
     let func = VMFunction {
+        name: String::new(),
+        id: 0,
         arity: 0,
+        variadic: false,
         nregs: 0,
         size: 0,
         instructions: Vec::new(),
+        source: None,
+        positions: Vec::new(),
     };
     let mut registers = Vec::with_capacity(50000);
     for _ in 0..registers.capacity() {
         registers.push(Val::Nil);
     }
-    return VMState {
+    let mut vm = VMState {
+        threads: vec![main_thread(&func)],
         func,
+        pc: 0,
+        reg_window: 0,
         registers,
         globals: HashMap::new(),
         literals: Vec::new(),
+        functions_loaded: 0,
         stack: Vec::new(),
+        max_depth: DEFAULT_MAX_DEPTH,
+        rust_call_depth: None,
+        fuel: None,
+        deadline: None,
+        breakpoints: Vec::new(),
+        memory: Memory::default(),
+        coroutines: Vec::new(),
+        coroutine: None,
+        thread: 0,
+        channels: Vec::new(),
+        quantum: DEFAULT_QUANTUM,
+        slice: 0,
         test_suite: Tester {
             tests: 0,
             passed: 0,
             checkv: (Val::Nil, "".to_string()),
         },
+        tracer: None,
+        profiler: None,
+        coverage: None,
+        output: Box::new(Stdout::new()),
+        input: Box::new(Stdin),
+        unread: String::new(),
+        ports: Vec::new(),
+        fs_root: None,
+        clock: Instant::now(),
+        random: Random::from_time(),
+        args: Vec::new(),
     };
+    define_primitives(&mut vm);
+    vm
+}
+
+impl Default for VMState {
+    fn default() -> Self {
+        init_vm_state()
+    }
 }
 
 impl VMState {
+    pub fn new() -> Self {
+        init_vm_state()
+    }
+    pub fn load_str(&mut self, input: &str) -> Result<VMFunction, VMError> {
+        loader::load_str(input, self)
+    }
+    pub fn load_reader(&mut self, reader: impl Read) -> Result<VMFunction, VMError> {
+        loader::load_reader(reader, self)
+    }
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<VMFunction, VMError> {
+        loader::load_bytes(bytes, self)
+    }
+    // Runs a loaded module from the start, as `vmrun::run` does.
+    pub fn run(&mut self, module: VMFunction) -> Result<Status, VMError> {
+        vmrun::run(self, module)
+    }
+    // Runs at most `n` more instructions of the program started by `run` or
+    // `start`, stopping early if it finishes, blocks or hits a breakpoint.
+    pub fn step(&mut self, n: u64) -> Result<Status, VMError> {
+        vmrun::run_until(self, Some(n), |_| false)
+    }
+    // Runs until `stop` holds after an instruction, or the program finishes,
+    // blocks or hits a breakpoint.
+    pub fn run_until(&mut self, stop: impl FnMut(&VMState) -> bool) -> Result<Status, VMError> {
+        vmrun::run_until(self, None, stop)
+    }
+    // Gets a loaded module ready for `step` or `run_until` without running
+    // any of it.
+    pub fn start(&mut self, module: VMFunction) {
+        vmrun::start(self, module)
+    }
+    // Lets the program open files under `directory`.
+    pub fn allow_fs(&mut self, directory: impl AsRef<Path>) -> io::Result<()> {
+        self.fs_root = Some(fs::canonicalize(directory)?);
+        Ok(())
+    }
+    // Makes a Rust function callable from VM code as the global `name`.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Val]) -> Result<Val, VMError> + 'static,
+    ) {
+        self.define_native_with_vm(name, arity, move |_, args| function(args));
+    }
+    // Like `define_native`, for functions that need the VM, for example to
+    // call a closure they were passed with `VMState::call`.
+    pub fn define_native_with_vm(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut VMState, &[Val]) -> Result<Val, VMError> + 'static,
+    ) {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        self.globals
+            .insert(Val::String(name.to_string()), Val::Native(Rc::new(native)));
+    }
+    // Calls a function, closure or primitive with `args` and returns its
+    // result. This works from a primitive in the middle of a run as well as
+    // between runs.
+    pub fn call(&mut self, function: &Val, args: &[Val]) -> Result<Val, VMError> {
+        vmrun::invoke(self, function, args)
+    }
+    pub fn global(&self, name: &str) -> Option<&Val> {
+        self.globals.get(&Val::String(name.to_string()))
+    }
+    pub fn location(&self) -> String {
+        match self.func.location(self.pc) {
+            Some(location) => format!("{} at {} ({})", self.func.name, self.pc, location),
+            None => format!("{} at {}", self.func.name, self.pc),
+        }
+    }
+    // The call stack from the innermost frame out, one line per frame. A run
+    // of frames at the same instruction, as in deep recursion, is shown once
+    // with a count, and only the top and bottom of a long backtrace are kept.
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut frames = vec![(&self.func, self.pc)];
+        frames.extend(
+            self.stack
+                .iter()
+                .rev()
+                .map(|act| (&act.fun, act.program_counter - 1)),
+        );
+        let mut lines = Vec::new();
+        let mut n = 0;
+        while n < frames.len() {
+            let (fun, pc) = frames[n];
+            let repeats = frames[n..]
+                .iter()
+                .take_while(|(f, p)| f.name == fun.name && *p == pc)
+                .count();
+            let mut line = format!("#{} {} at {}", n, fun.name, pc);
+            if let Some(location) = fun.location(pc) {
+                line.push_str(&format!(" ({})", location));
+            }
+            if repeats > 1 {
+                line.push_str(&format!(" [{} frames]", repeats));
+            }
+            lines.push(line);
+            n += repeats;
+        }
+        if lines.len() > 2 * BACKTRACE_ENDS {
+            let elided = lines.len() - 2 * BACKTRACE_ENDS;
+            lines.splice(
+                BACKTRACE_ENDS..lines.len() - BACKTRACE_ENDS,
+                vec![format!("... {} more lines", elided)],
+            );
+        }
+        lines
+    }
+    // Every function loaded by `.load N function`, in load order.
+    pub fn functions(&self) -> impl Iterator<Item = &VMFunction> {
+        self.literals.iter().filter_map(|literal| match literal {
+            Val::VMFunction(f) => Some(f),
+            _ => None,
+        })
+    }
     pub fn literal_slot(&mut self, v: Val) -> usize {
         self.literals.push(v);
         self.literals.len() - 1
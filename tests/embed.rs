@@ -0,0 +1,721 @@
+use std::time::{Duration, Instant};
+use svm::input::Script;
+use svm::output::Buffer;
+use svm::primitives::Random;
+use svm::repl;
+use svm::{FromVal, IntoVal, Status, VMError, VMState, Val};
+
+// Sets the global `answer` to 6 * 7 and checks it.
+const PROGRAM: &str = ".load module 6
+loadliteral 1 6
+loadliteral 2 7
+* 3 1 2
+setglobal 3 string 6 97 110 115 119 101 114
+check-assert 3 string 6 97 110 115 119 101 114
+halt
+";
+
+#[test]
+fn runs_a_program_and_exposes_its_globals_and_tests() {
+    let mut vm = VMState::new();
+    let module = vm.load_str(PROGRAM).unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    assert_eq!(vm.global("answer"), Some(&Val::Num(42)));
+    assert_eq!(vm.global("question"), None);
+    assert_eq!(vm.test_suite.tests(), 1);
+    assert_eq!(vm.test_suite.passed(), 1);
+}
+
+#[test]
+fn loads_from_readers_and_bytes() {
+    let mut vm = VMState::new();
+    let module = vm.load_reader(PROGRAM.as_bytes()).unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    let module = vm.load_bytes(PROGRAM.as_bytes()).unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+}
+
+#[test]
+fn reports_load_errors() {
+    let mut vm = VMState::new();
+    assert_eq!(
+        vm.load_str(".load module 2\nhalt\nfrobnicate 1\n")
+            .unwrap_err(),
+        VMError::Load("line 3: can't parse frobnicate".to_string())
+    );
+}
+
+#[test]
+fn reports_malformed_instructions_as_runtime_errors() {
+    let cases = [
+        ("getclslot 2 1 5", "Closure slot 5 is out of range"),
+        ("setclslot 1 2 5", "Closure slot 5 is out of range"),
+        ("check-assert 1 5", "5 isn't a string"),
+    ];
+    for (instruction, error) in cases {
+        let mut vm = VMState::new();
+        let module = vm
+            .load_str(&format!(
+                ".load module 4
+.load 0 function 0 1 f
+return 0
+mkclosure 1 0 1
+{}
+halt
+",
+                instruction
+            ))
+            .unwrap();
+        assert_eq!(
+            vm.run(module),
+            Err(VMError::runtime(error)),
+            "{}",
+            instruction
+        );
+    }
+}
+
+#[test]
+fn reports_numbers_out_of_range_as_load_errors() {
+    let cases = [
+        (
+            ".load module 2\nloadliteral 1 99999999999\nhalt\n",
+            2,
+            "99999999999",
+        ),
+        (
+            ".load module 2\nloadliteral 1 string 1 300\nhalt\n",
+            2,
+            "300",
+        ),
+        (".load module 2\nloadliteral 1 string 1 ab\nhalt\n", 2, "ab"),
+        (
+            ".load module 2\ngoto -99999999999\nhalt\n",
+            2,
+            "-99999999999",
+        ),
+        (".load module 99999999999\nhalt\n", 1, "99999999999"),
+        (
+            ".load module 1\n.line 99999999999 1\nhalt\n",
+            2,
+            "99999999999",
+        ),
+        (
+            ".load module 1\nmov 99999999999999999999 1\n",
+            2,
+            "99999999999999999999",
+        ),
+        (
+            ".load module 1\n.load 0 function 0 99999999999\nhalt\n",
+            2,
+            "99999999999",
+        ),
+    ];
+    for (source, line, near) in cases.iter() {
+        let mut vm = VMState::new();
+        assert_eq!(
+            vm.load_str(source).unwrap_err(),
+            VMError::Load(format!("line {}: can't parse {}", line, near)),
+            "loading {:?}",
+            source
+        );
+    }
+}
+
+#[test]
+fn calls_native_functions() {
+    let mut vm = VMState::new();
+    vm.define_native("add3", 3, |args| {
+        let mut sum = 0;
+        for arg in args {
+            sum += arg.as_num()?;
+        }
+        Ok(Val::Num(sum))
+    });
+    let module = vm
+        .load_str(
+            ".load module 7
+getglobal 1 string 4 97 100 100 51
+loadliteral 2 1
+loadliteral 3 2
+loadliteral 4 3
+call 5 1 4
+setglobal 5 string 6 97 110 115 119 101 114
+halt
+",
+        )
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    assert_eq!(vm.global("answer"), Some(&Val::Num(6)));
+}
+
+#[test]
+fn tail_calls_native_functions() {
+    let mut vm = VMState::new();
+    vm.define_native("double", 1, |args| Ok(Val::Num(args[0].as_num()? * 2)));
+    let module = vm
+        .load_str(
+            ".load module 5
+.load 0 function 1 3 f
+getglobal 2 string 6 100 111 117 98 108 101
+mov 3 1
+tailcall 2 3
+loadliteral 1 21
+call 2 0 1
+setglobal 2 string 6 97 110 115 119 101 114
+halt
+",
+        )
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    assert_eq!(vm.global("answer"), Some(&Val::Num(42)));
+    assert!(vm.stack.is_empty());
+}
+
+#[test]
+fn checks_native_arity() {
+    let mut vm = VMState::new();
+    vm.define_native("double", 1, |args| Ok(Val::Num(args[0].as_num()? * 2)));
+    let module = vm
+        .load_str(
+            ".load module 2
+getglobal 1 string 6 100 111 117 98 108 101
+call 2 1 1
+",
+        )
+        .unwrap();
+    assert_eq!(
+        vm.run(module),
+        Err(VMError::runtime("double expects 1 argument, got 0"))
+    );
+}
+
+#[test]
+fn calls_vm_functions_from_rust() {
+    let mut vm = VMState::new();
+    let module = vm
+        .load_str(
+            ".load module 3
+.load 0 function 2 2 add
++ 3 1 2
+return 3
+setglobal 0 string 3 97 100 100
+halt
+",
+        )
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    let add = vm.global("add").unwrap().clone();
+    let sum = vm.call(&add, &[1.into_val(), 2.into_val()]).unwrap();
+    assert_eq!(i32::from_val(&sum), Ok(3));
+    assert_eq!(
+        vm.call(&add, &[1.into_val()]),
+        Err(VMError::runtime("add expects 2 arguments, got 1"))
+    );
+}
+
+#[test]
+fn calls_closures_from_rust() {
+    // add10 adds the 10 it captured to its argument, reading the capture
+    // through r0 as closures do.
+    let mut vm = VMState::new();
+    let module = vm
+        .load_str(&format!(
+            ".load module 6
+.load 0 function 1 3 adder
+getclslot 2 0 0
++ 3 1 2
+return 3
+mkclosure 1 0 1
+loadliteral 2 10
+setclslot 1 2 0
+setglobal 1 {}
+halt
+",
+            string_literal("add10")
+        ))
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    let add10 = vm.global("add10").unwrap().clone();
+    let sum = vm.call(&add10, &[5.into_val()]).unwrap();
+    assert_eq!(i32::from_val(&sum), Ok(15));
+}
+
+#[test]
+fn primitives_can_call_back_into_the_vm() {
+    let mut vm = VMState::new();
+    vm.define_native_with_vm("map", 2, |vm, args| {
+        let items = Vec::<Val>::from_val(&args[1])?;
+        let mapped = items
+            .iter()
+            .map(|x| vm.call(&args[0], std::slice::from_ref(x)))
+            .collect::<Result<Vec<Val>, VMError>>()?;
+        Ok(mapped.into_val())
+    });
+    let module = vm
+        .load_str(
+            ".load module 13
+.load 0 function 1 2 square
+* 2 1 1
+return 2
+getglobal 1 string 3 109 97 112
+mov 2 0
+loadliteral 3 emptylist
+loadliteral 4 3
+cons 3 4 3
+loadliteral 4 2
+cons 3 4 3
+loadliteral 4 1
+cons 3 4 3
+call 5 1 3
+setglobal 5 string 6 97 110 115 119 101 114
+halt
+",
+        )
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    let answer = vm.global("answer").unwrap();
+    assert_eq!(Vec::<i32>::from_val(answer), Ok(vec![1, 4, 9]));
+}
+
+#[test]
+fn continuations_cannot_escape_calls_from_rust() {
+    // The top level saves its continuation in k, then has callit run a
+    // thunk that invokes k from inside vm.call.
+    let mut vm = VMState::new();
+    vm.define_native_with_vm("callit", 1, |vm, args| vm.call(&args[0], &[]));
+    let module = vm
+        .load_str(&format!(
+            ".load module 8
+.load 0 function 1 3 save
+setglobal 1 {k}
+loadliteral 2 0
+return 2
+.load 1 function 0 4 thunk
+getglobal 1 {k}
+loadliteral 2 42
+call 3 1 2
+return 3
+mov 4 0
+callcc 3 4
+getglobal 5 {callit}
+mov 6 1
+call 7 5 6
+halt
+",
+            k = string_literal("k"),
+            callit = string_literal("callit")
+        ))
+        .unwrap();
+    assert_eq!(
+        vm.run(module),
+        Err(VMError::runtime(
+            "Can't invoke a continuation captured outside the current call from Rust"
+        ))
+    );
+    // Nor can it escape a call made from the top level.
+    let k = vm.global("k").unwrap().clone();
+    assert!(vm.call(&k, &[1.into_val()]).is_err());
+}
+
+#[test]
+fn calls_from_rust_stop_on_fuel_and_deadline() {
+    // spin loops forever; the top level has callit run it.
+    let mut vm = VMState::new();
+    vm.define_native_with_vm("callit", 1, |vm, args| vm.call(&args[0], &[]));
+    let module = vm
+        .load_str(&format!(
+            ".load module 5
+.load 0 function 0 1 spin
+goto 0
+setglobal 0 {spin}
+getglobal 1 {callit}
+mov 2 0
+call 3 1 2
+",
+            spin = string_literal("spin"),
+            callit = string_literal("callit")
+        ))
+        .unwrap();
+    vm.fuel = Some(1000);
+    assert_eq!(vm.run(module), Ok(Status::OutOfFuel));
+    let spin = vm.global("spin").unwrap().clone();
+    vm.fuel = Some(1000);
+    assert_eq!(vm.call(&spin, &[]), Err(VMError::OutOfFuel));
+    vm.fuel = None;
+    vm.deadline = Some(Instant::now());
+    assert_eq!(vm.call(&spin, &[]), Err(VMError::TimedOut));
+}
+
+#[test]
+fn converts_rust_values() {
+    let v = vec![Some("a".to_string()), None].into_val();
+    assert_eq!(
+        Vec::<Option<String>>::from_val(&v),
+        Ok(vec![Some("a".to_string()), None])
+    );
+    assert_eq!(bool::from_val(&true.into_val()), Ok(true));
+    assert_eq!(usize::from_val(&7.into_val()), Ok(7));
+    assert!(usize::from_val(&(-7).into_val()).is_err());
+    assert!(i32::from_val(&"seven".into_val()).is_err());
+}
+
+// Counts up in the global `n` forever.
+const COUNTER: &str = ".load module 5
+loadliteral 1 0
+loadliteral 2 1
++ 1 1 2
+setglobal 1 string 1 110
+goto -2
+";
+
+#[test]
+fn runs_a_few_instructions_at_a_time() {
+    let mut vm = VMState::new();
+    let module = vm.load_str(COUNTER).unwrap();
+    vm.start(module);
+    assert_eq!(vm.step(4), Ok(Status::Yielded));
+    assert_eq!(vm.global("n"), Some(&Val::Num(1)));
+    assert_eq!(vm.step(3), Ok(Status::Yielded));
+    assert_eq!(vm.global("n"), Some(&Val::Num(2)));
+    assert_eq!(
+        vm.run_until(|vm| vm.global("n") == Some(&Val::Num(10))),
+        Ok(Status::Breakpoint)
+    );
+    assert_eq!(vm.global("n"), Some(&Val::Num(10)));
+
+    vm.breakpoints.push((vm.func.name.clone(), 3));
+    assert_eq!(vm.run_until(|_| false), Ok(Status::Breakpoint));
+    assert_eq!(vm.pc, 3);
+    assert_eq!(vm.step(1), Ok(Status::Yielded));
+    assert_eq!(vm.global("n"), Some(&Val::Num(11)));
+    assert_eq!(vm.step(100), Ok(Status::Breakpoint));
+    assert_eq!(vm.global("n"), Some(&Val::Num(11)));
+
+    vm.breakpoints.clear();
+    let module = vm.load_str(PROGRAM).unwrap();
+    vm.start(module);
+    assert_eq!(vm.step(100), Ok(Status::Halted));
+}
+
+#[test]
+fn captures_what_a_program_prints() {
+    let mut vm = VMState::new();
+    let printed = Buffer::new();
+    vm.output = Box::new(printed.clone());
+    let module = vm
+        .load_str(
+            ".load module 6
+loadliteral 1 42
+print 1
+check 1 string 1 120
+loadliteral 2 7
+expect 2 string 1 55
+halt
+",
+        )
+        .unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    vm.test_suite.report_tests(&mut *vm.output).unwrap();
+    assert_eq!(
+        printed.take(),
+        "42\nGot Num(7): Expected: Num(42)\nSome tests failed\n"
+    );
+    assert_eq!(printed.contents(), "");
+}
+
+// Reads a number, the rest of its line, a datum and a line, and sets the
+// global `x` to each in turn, checking that the next read is at the end.
+const READER: &str = ".load module 11
+read-number 1
+setglobal 1 string 1 120
+read-line 1
+setglobal 1 string 1 120
+read 1
+setglobal 1 string 1 120
+read-line 1
+setglobal 1 string 1 120
+read 1
+eof? 2 1
+setglobal 2 string 1 120
+";
+
+#[test]
+fn reads_scripted_input() {
+    let mut vm = VMState::new();
+    vm.input = Box::new(Script::new("12 rest\n(a (1 . #t)\n \"b c\") last\n"));
+    let module = vm.load_str(READER).unwrap();
+    vm.start(module);
+    let x = |vm: &VMState| vm.global("x").cloned();
+    assert_eq!(vm.step(2), Ok(Status::Yielded));
+    assert_eq!(x(&vm), Some(Val::Num(12)));
+    assert_eq!(vm.step(2), Ok(Status::Yielded));
+    assert_eq!(x(&vm), Some(" rest".into_val()));
+    assert_eq!(vm.step(2), Ok(Status::Yielded));
+    let pair = Val::Cons(Box::new(Val::Num(1)), Box::new(Val::Bool(true)));
+    assert_eq!(
+        x(&vm),
+        Some(vec!["a".into_val(), pair, "b c".into_val()].into_val())
+    );
+    assert_eq!(vm.step(2), Ok(Status::Yielded));
+    assert_eq!(x(&vm), Some(" last".into_val()));
+    assert_eq!(vm.step(100), Ok(Status::Halted));
+    assert_eq!(x(&vm), Some(Val::Bool(true)));
+}
+
+#[test]
+fn blocks_until_there_is_input() {
+    let mut vm = VMState::new();
+    let input = Script::open();
+    vm.input = Box::new(input.clone());
+    let module = vm.load_str(READER).unwrap();
+    vm.start(module);
+    assert_eq!(vm.step(100), Ok(Status::BlockedOnInput));
+    input.push("3");
+    assert_eq!(vm.step(100), Ok(Status::BlockedOnInput));
+    input.push("4\n(1\n");
+    assert_eq!(vm.step(100), Ok(Status::BlockedOnInput));
+    assert_eq!(vm.global("x"), Some(&"".into_val()));
+    input.push("2)\n");
+    input.close();
+    assert_eq!(vm.step(100), Ok(Status::Halted));
+    assert_eq!(vm.global("x"), Some(&Val::Bool(true)));
+
+    let mut vm = VMState::new();
+    vm.input = Box::new(Script::new("x"));
+    let module = vm.load_str(READER).unwrap();
+    assert_eq!(
+        vm.run(module),
+        Err(VMError::runtime("read-number: x isn't a number"))
+    );
+}
+
+// A literal for the string `s`.
+fn string_literal(s: &str) -> String {
+    let bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+    format!("string {} {}", bytes.len(), bytes.join(" "))
+}
+
+// Writes two lines to the file `path`, then reads them back into the
+// globals `line` and `datum`, and the end of the file into `end`.
+fn file_program(path: &str) -> String {
+    format!(
+        ".load module 15
+loadliteral 1 {}
+open-output 2 1
+loadliteral 3 {}
+port-write-line 2 3
+loadliteral 3 42
+port-write-line 2 3
+close-port 2
+open-input 4 1
+port-read-line 5 4
+setglobal 5 {}
+port-read 5 4
+setglobal 5 {}
+port-read 5 4
+setglobal 5 {}
+halt
+",
+        string_literal(path),
+        string_literal("a,b"),
+        string_literal("line"),
+        string_literal("datum"),
+        string_literal("end"),
+    )
+}
+
+#[test]
+fn opens_files_only_where_allowed() {
+    let dir = std::env::temp_dir().join(format!("svm-files-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let path = dir.join("data.txt");
+    let program = file_program(path.to_str().unwrap());
+
+    let mut vm = VMState::new();
+    let module = vm.load_str(&program).unwrap();
+    assert_eq!(
+        vm.run(module),
+        Err(VMError::runtime(format!(
+            "Can't open {}: file access is disabled (see --allow-fs)",
+            path.display()
+        )))
+    );
+
+    let mut vm = VMState::new();
+    vm.allow_fs(dir.join("sub")).unwrap();
+    let module = vm.load_str(&program).unwrap();
+    let escaping = dir.join("sub").join("..").join("data.txt");
+    let module_escaping = vm
+        .load_str(&file_program(escaping.to_str().unwrap()))
+        .unwrap();
+    let outside = format!(
+        "is outside {}",
+        dir.join("sub").canonicalize().unwrap().display()
+    );
+    for module in [module, module_escaping] {
+        let error = vm.run(module).unwrap_err().to_string();
+        assert!(error.ends_with(&outside), "{}", error);
+    }
+
+    let mut vm = VMState::new();
+    vm.allow_fs(&dir).unwrap();
+    let module = vm.load_str(&program).unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    assert_eq!(vm.global("line"), Some(&"a,b".into_val()));
+    assert_eq!(vm.global("datum"), Some(&Val::Num(42)));
+    assert_eq!(vm.global("end"), Some(&Val::Eof));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\n42\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn opens_relative_paths_under_the_root_and_limits_open_ports() {
+    let dir = std::env::temp_dir().join(format!("svm-ports-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut vm = VMState::new();
+    vm.allow_fs(&dir).unwrap();
+    let module = vm.load_str(&file_program("data.txt")).unwrap();
+    assert_eq!(vm.run(module), Ok(Status::Halted));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("data.txt")).unwrap(),
+        "a,b\n42\n"
+    );
+
+    // Opening and closing a port over and over reuses its slot; opening
+    // without closing runs out of them.
+    let reopening = vm
+        .load_str(&format!(
+            ".load module 5
+loadliteral 1 {}
+open-input 2 1
+close-port 2
+goto -2
+halt
+",
+            string_literal("data.txt")
+        ))
+        .unwrap();
+    vm.fuel = Some(1000);
+    assert_eq!(vm.run(reopening), Ok(Status::OutOfFuel));
+    vm.fuel = None;
+    let leaking = vm
+        .load_str(&format!(
+            ".load module 4
+loadliteral 1 {}
+open-input 2 1
+goto -1
+halt
+",
+            string_literal("data.txt")
+        ))
+        .unwrap();
+    assert_eq!(
+        vm.run(leaking),
+        Err(VMError::runtime(
+            "Can't open data.txt: 64 files are open already"
+        ))
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn provides_the_clock_random_numbers_and_arguments() {
+    let mut vm = VMState::new();
+    vm.args = vec!["in.csv".to_string(), "-v".to_string()];
+    let arguments = vm.global("command-line-arguments").unwrap().clone();
+    assert_eq!(
+        vm.call(&arguments, &[]),
+        Ok(vec!["in.csv", "-v"].into_val())
+    );
+
+    let clock = vm.global("clock-ms").unwrap().clone();
+    let before = i32::from_val(&vm.call(&clock, &[]).unwrap()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let after = i32::from_val(&vm.call(&clock, &[]).unwrap()).unwrap();
+    assert!(after >= before + 5, "{} then {}", before, after);
+
+    let random = vm.global("random").unwrap().clone();
+    let draw = |vm: &mut VMState| -> Vec<i32> {
+        vm.random = Random::new(42);
+        (0..20)
+            .map(|_| i32::from_val(&vm.call(&random, &[10.into_val()]).unwrap()).unwrap())
+            .collect()
+    };
+    let numbers = draw(&mut vm);
+    assert_eq!(draw(&mut vm), numbers);
+    assert!(numbers.iter().all(|n| (0..10).contains(n)));
+    assert!(numbers.iter().any(|n| *n != numbers[0]));
+    assert_eq!(
+        vm.call(&random, &[0.into_val()]),
+        Err(VMError::runtime("random expects a positive number, got 0"))
+    );
+}
+
+#[test]
+fn runs_modules_one_at_a_time_in_a_repl() {
+    let session = ".load module 3
+loadliteral 1 5
+setglobal 1 string 1 120
+halt
+
+.load module 3
+.load 2 function 1 2 double
++ 2 1 1
+return 2
+setglobal 2 string 6 100 111 117 98 108 101
+halt
+oops
+.load module 4
+getglobal 1 string 6 100 111 117 98 108 101
+getglobal 2 string 1 120
+call 1 1 2
+return 1
+.load module 2
+getglobal 1 string 1 121
+return 1
+.load module 1
+frobnicate 1
+.load module 2
+getglobal 1 string 1 120
+return 1
+.load module 2
+";
+    let mut vm = VMState::new();
+    let printed = Buffer::new();
+    vm.output = Box::new(printed.clone());
+    repl::run(&mut vm, session.as_bytes(), false, None).unwrap();
+    assert_eq!(
+        printed.take(),
+        "error: expected .load module, got oops
+10
+error in module at 0: Undefined global y
+error loading module: line 2: can't parse frobnicate
+5
+error: the input ends inside a module
+"
+    );
+}
+
+#[test]
+fn times_out_each_repl_module_on_its_own() {
+    let session = ".load module 1
+goto 0
+.load module 2
+loadliteral 1 5
+return 1
+";
+    let mut vm = VMState::new();
+    let printed = Buffer::new();
+    vm.output = Box::new(printed.clone());
+    let modules = repl::run(
+        &mut vm,
+        session.as_bytes(),
+        false,
+        Some(Duration::from_millis(50)),
+    )
+    .unwrap();
+    assert_eq!(printed.take(), "error in module at 0: timed out\n5\n");
+    assert_eq!(modules.len(), 2);
+}